@@ -1,105 +1,925 @@
 extern crate cpal;
 extern crate hound;
 
+mod ring_buffer;
+
 use std::result::Result;
-use std::io::Error;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{self, IsTerminal};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::f32;
-use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::thread;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
 
-use spc700_core::SPC700;
+use spc700_core::{SPC700, Spc700Error, SpcArchive, Spc2File, SongEnd, SongEndOptions, PostMix, FadeOut, FadeCurve, Id666, MidiOptions, MidiInstrument, DspLog, DspReplay, SAMPLE_RATE, duration_to_samples, read_sidecar_gain};
+use ring_buffer::{ring_buffer, Producer, Consumer};
 
-const SAMPLE_RATE: u32 = 32000;
-const INPUT_SAMPLING_RATE: usize = 32000;
-const BUFFER_SIZE: usize = INPUT_SAMPLING_RATE * 8;
+// number of stereo frames
+const RING_BUFFER_FRAMES: usize = 8192;
+const BLOCK_FRAMES: usize = 512;
+const SEEK_STEP: Duration = Duration::from_secs(5);
+const TEMPO_STEP: f32 = 0.05;
+const DEFAULT_DURATION_MS: u64 = 100;
+// rendered after the last MIDI message for released notes
+const SYNTH_TAIL: Duration = Duration::from_secs(2);
 
 pub struct Amplifier;
 impl Amplifier {
-  pub fn play(core: SPC700, duration: u64) {
-    let device = cpal::default_host().default_output_device().expect("no output device available");
-
-    // 32000Hzの再生に対応しているconfigを探す。
-    // 32000HzはSPC700が再生時に使用するサンプリングレート
-    let config = device.supported_output_configs()
-      .unwrap()
-      .find(|config| {
-        let cpal::SampleRate(max) = config.max_sample_rate();
-        let cpal::SampleRate(min) = config.min_sample_rate();
-        min <= SAMPLE_RATE && SAMPLE_RATE <= max
-      })
-      .expect("there are no current device configs to play on 32000Hz.")
-      .with_sample_rate(cpal::SampleRate(SAMPLE_RATE));
-
-    let format = config.sample_format();
-    let config = config.config();
-    let stream = match format {
-      cpal::SampleFormat::F32 => {
-        build_stream::<f32>(&device, &config, core)
-      }
-      cpal::SampleFormat::I16 => {
-        build_stream::<i16>(&device, &config, core)
-      }
-      cpal::SampleFormat::U16 => {
-        build_stream::<u16>(&device, &config, core)
+  // Plays until the song position reaches `end`.
+  pub fn play(core: SPC700, end: Duration) {
+    let (producer, consumer) = ring_buffer::<i16>(RING_BUFFER_FRAMES * 2);
+    let is_drained = Arc::new(AtomicBool::new(false));
+    let stream = open_stream(consumer, is_drained.clone());
+    let (commands, receiver) = mpsc::channel();
+    let is_running = Arc::new(AtomicBool::new(true));
+    let emulation = spawn_emulation(core, end, producer, receiver, is_running.clone());
+
+    stream.play().unwrap();
+
+    let keyboard = Keyboard::new();
+    if keyboard.is_some() {
+      println!("[←/b] rewind {}s  [→/f] forward {}s  [-/+] tempo  [q] quit\r", SEEK_STEP.as_secs(), SEEK_STEP.as_secs());
+    }
+
+    while !emulation.is_finished() {
+      let command = match &keyboard {
+        Some(keyboard) => keyboard.poll(Duration::from_millis(10)),
+        None => {
+          thread::sleep(Duration::from_millis(10));
+          None
+        }
+      };
+
+      match command {
+        Some(Command::Quit) => break,
+        Some(command) => { let _ = commands.send(command); }
+        None => (),
       }
-    };
+    }
+
+    // wait for samples remaining in ring buffer if the song reaches the end
+    if emulation.is_finished() {
+      wait_until_drained(&is_drained);
+    }
+
+    // stop audio callback first, and then emulation thread
+    drop(keyboard);
+    drop(stream);
+    is_running.store(false, Ordering::Release);
+    if emulation.join().is_err() {
+      eprintln!("emulation thread panicked");
+    }
+  }
 
+  // Plays interleaved stereo samples to the end.
+  pub fn play_samples(samples: &[i16]) {
+    let (mut producer, consumer) = ring_buffer::<i16>(RING_BUFFER_FRAMES * 2);
+    let is_drained = Arc::new(AtomicBool::new(false));
+    let stream = open_stream(consumer, is_drained.clone());
     stream.play().unwrap();
 
-    thread::sleep(std::time::Duration::from_millis(duration));
+    for block in samples.chunks(BLOCK_FRAMES * 2) {
+      while producer.free_len() < block.len() {
+        thread::sleep(Duration::from_millis(1));
+      }
+      producer.push_slice(block);
+    }
+
+    drop(producer);
+    wait_until_drained(&is_drained);
+  }
+}
+
+// Waits for the audio callback to play all samples after producer is dropped.
+// Gives up after twice the ring buffer length in case the device stops calling back.
+fn wait_until_drained(is_drained: &AtomicBool) {
+  let timeout = Duration::from_millis((RING_BUFFER_FRAMES as u64 * 2000) / SAMPLE_RATE as u64);
+  let mut waited = Duration::ZERO;
+  while !is_drained.load(Ordering::Acquire) && waited < timeout {
+    thread::sleep(Duration::from_millis(1));
+    waited += Duration::from_millis(1);
+  }
+}
+
+// Opens default output device on 32000Hz, and plays samples popped from `consumer`.
+// `is_drained` is set when producer is dropped and all samples are played.
+fn open_stream(consumer: Consumer<i16>, is_drained: Arc<AtomicBool>) -> cpal::Stream {
+  let device = cpal::default_host().default_output_device().expect("no output device available");
+
+  // 32000Hzの再生に対応しているconfigを探す。
+  // 32000HzはSPC700が再生時に使用するサンプリングレート
+  let config = device.supported_output_configs()
+    .unwrap()
+    .find(|config| {
+      let cpal::SampleRate(max) = config.max_sample_rate();
+      let cpal::SampleRate(min) = config.min_sample_rate();
+      min <= SAMPLE_RATE && SAMPLE_RATE <= max
+    })
+    .expect("there are no current device configs to play on 32000Hz.")
+    .with_sample_rate(cpal::SampleRate(SAMPLE_RATE));
+
+  let format = config.sample_format();
+  let config = config.config();
+  match format {
+    cpal::SampleFormat::F32 => {
+      build_stream::<f32>(&device, &config, consumer, is_drained)
+    }
+    cpal::SampleFormat::I16 => {
+      build_stream::<i16>(&device, &config, consumer, is_drained)
+    }
+    cpal::SampleFormat::U16 => {
+      build_stream::<u16>(&device, &config, consumer, is_drained)
+    }
   }
 }
 
+enum Command {
+  Forward,
+  Rewind,
+  // relative change of tempo
+  Tempo(f32),
+  Quit,
+}
+
+// Reads key events in raw mode. Raw mode is disabled when dropped.
+struct Keyboard;
+impl Keyboard {
+  // Returns None if stdin is not a terminal.
+  fn new() -> Option<Keyboard> {
+    if !io::stdin().is_terminal() || terminal::enable_raw_mode().is_err() {
+      return None;
+    }
+
+    Some(Keyboard)
+  }
+
+  fn poll(&self, timeout: Duration) -> Option<Command> {
+    if !event::poll(timeout).unwrap_or(false) {
+      return None;
+    }
+
+    match event::read() {
+      Ok(Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. })) => match code {
+        KeyCode::Right | KeyCode::Char('f') => Some(Command::Forward),
+        KeyCode::Left | KeyCode::Char('b') => Some(Command::Rewind),
+        KeyCode::Char('+') | KeyCode::Char('=') => Some(Command::Tempo(TEMPO_STEP)),
+        KeyCode::Char('-') => Some(Command::Tempo(-TEMPO_STEP)),
+        KeyCode::Char('q') | KeyCode::Esc => Some(Command::Quit),
+        KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => Some(Command::Quit),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+}
+
+impl Drop for Keyboard {
+  fn drop(&mut self) {
+    let _ = terminal::disable_raw_mode();
+  }
+}
+
+// Emulation runs ahead of the audio callback and fills ring buffer by blocks.
+fn spawn_emulation(
+  mut core: SPC700,
+  end: Duration,
+  mut producer: Producer<i16>,
+  commands: Receiver<Command>,
+  is_running: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+  thread::spawn(move || {
+    let mut block = [0; BLOCK_FRAMES * 2];
+
+    while is_running.load(Ordering::Acquire) && !producer.is_abandoned() && core.position() < end {
+      for command in commands.try_iter() {
+        let result = match command {
+          Command::Forward => { core.seek(SEEK_STEP); Ok(()) }
+          Command::Rewind => core.seek_to(core.position().saturating_sub(SEEK_STEP)),
+          Command::Tempo(delta) => {
+            core.set_tempo(core.tempo() + delta);
+            println!("tempo: {:.2}\r", core.tempo());
+            Ok(())
+          }
+          Command::Quit => Ok(()),
+        };
+
+        if let Err(err) = result {
+          eprintln!("{}\r", err);
+        }
+      }
+
+      if producer.free_len() < block.len() {
+        thread::sleep(Duration::from_millis(1));
+        continue;
+      }
+
+      core.render(&mut block);
+      producer.push_slice(&block);
+
+      if let Some(err) = core.fault() {
+        eprintln!("{}\r", err);
+        break;
+      }
+    }
+  })
+}
+
 fn build_stream<T: cpal::Sample + std::marker::Send + 'static>(
   device: &cpal::Device,
   config: &cpal::StreamConfig,
-  mut core: SPC700
+  mut consumer: Consumer<i16>,
+  is_drained: Arc<AtomicBool>,
 ) -> cpal::Stream {  
   let channels = config.channels as usize;
-
-  let (tx, rx) = mpsc::sync_channel(BUFFER_SIZE);
-
-  thread::spawn(move || {
-    loop {
-      let (left, right) = core.next_sample();
-      let output = ((left as i32 + right as i32) / 2) as i16;
-      match tx.send(T::from(&output)) {
-        Ok(_) => {},
-        Err(err) => { 
-          println!("{:?}", err);
-          std::process::exit(0);
-        },
-      }
-    } 
-  });
+  let mut frames = vec![0; BLOCK_FRAMES * 2];
 
   let error_callback = |err| eprintln!("an error occurred on stream: {}", err);  
   let data_callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-    for dsts in data.chunks_mut(channels) {
-      dsts.fill(rx.recv().unwrap());
+    for dsts in data.chunks_mut(channels * BLOCK_FRAMES) {
+      let frame_count = dsts.len() / channels;
+      // frames after popped ones are underrun, so they are filled by silence.
+      let popped = consumer.pop_or_fill(&mut frames[..frame_count * 2], 0);
+      if popped < frame_count * 2 && consumer.is_abandoned() {
+        is_drained.store(true, Ordering::Release);
+      }
+      for (dst, frame) in dsts.chunks_mut(channels).zip(frames.chunks_exact(2)) {
+        write_frame(dst, frame[0], frame[1]);
+      }
     }
   };
 
   device.build_output_stream(config, data_callback, error_callback).unwrap()
 }
+
+fn write_frame<T: cpal::Sample>(dst: &mut [T], left: i16, right: i16) {
+  match dst {
+    [mono] => *mono = T::from(&(((left as i32 + right as i32) / 2) as i16)),
+    [l, r, rest @ ..] => {
+      *l = T::from(&left);
+      *r = T::from(&right);
+      rest.fill(T::from(&0i16));
+    }
+    [] => (),
+  }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Curve {
+    Linear,
+    Log,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LoudnessTarget {
+    Xid6,
+    Sidecar,
+}
+
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long, default_value_t = 100)]
-    duration: u64,
+    #[command(subcommand)]
+    action: Option<Action>,
+
+    /// Length in milliseconds to play [default: 100].
+    /// Loudness analysis, MIDI, XM and SoundFont export default to the whole song by ID666 length or detected song end
+    #[arg(short, long)]
+    duration: Option<u64>,
+
+    /// Position in milliseconds to start playing or rendering from
+    #[arg(short, long, default_value_t = 0)]
+    start: u64,
+
+    /// Render to a WAV file instead of playing
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Detect the song loop and stop after looping this many times (overrides duration)
+    #[arg(long)]
+    loops: Option<u32>,
+
+    /// Tempo scale without changing pitch (0.25 to 4.0)
+    #[arg(long, default_value_t = 1.0)]
+    tempo: f32,
+
+    /// Transpose in semitones without changing tempo
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    transpose: f32,
+
+    /// Allow transposed pitch to exceed the 14-bit hardware limit
+    #[arg(long)]
+    unlimited_pitch: bool,
+
+    /// Fade out length in milliseconds after the last loop
+    #[arg(long, default_value_t = 5000)]
+    fade: u64,
+
+    /// Fade out curve
+    #[arg(long, value_enum, default_value_t = Curve::Linear)]
+    fade_curve: Curve,
+
+    /// Gain in dB applied before saturation (defaults to the stored ReplayGain)
+    #[arg(long, allow_negative_numbers = true)]
+    gain: Option<f32>,
+
+    /// Soft clip samples near full scale instead of hard clipping
+    #[arg(long)]
+    soft_clip: bool,
+
+    /// Apply generic analog output filter (DC block and treble roll-off)
+    #[arg(long)]
+    filter: bool,
+
+    /// Track index or name to play in an archive (.rsn, .zip, .spc2)
+    #[arg(short, long)]
+    track: Option<String>,
+
+    /// List tracks in the file and exit
+    #[arg(short, long)]
+    list: bool,
+
+    /// Measure loudness and ReplayGain of the track instead of playing
+    #[arg(long)]
+    analyze: bool,
+
+    /// Write measured ReplayGain into xid6 of the SPC file or a sidecar file (implies --analyze)
+    #[arg(long, value_enum)]
+    write_loudness: Option<LoudnessTarget>,
+
+    /// Transcribe the song into a MIDI file instead of playing
+    #[arg(long)]
+    midi: Option<String>,
+
+    /// MIDI note played by pitch 0x1000 of an instrument as SRCN=NOTE (repeatable)
+    #[arg(long, value_parser = parse_base_note)]
+    midi_base: Vec<(u8, f32)>,
+
+    /// Transcribe the song into an XM module instead of playing
+    #[arg(long)]
+    xm: Option<String>,
+
+    /// Build a SoundFont from instruments used in the song instead of playing
+    #[arg(long)]
+    sf2: Option<String>,
+
+    /// Record DSP writes with initial RAM and DSP registers into a log file instead of playing
+    #[arg(long)]
+    record_dsp: Option<String>,
+
+    #[arg(required = true)]
+    file: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Play an instrument of the song without running its program
+    Audition(AuditionArgs),
+    /// Play a MIDI file with instruments of the song
+    Synth(SynthArgs),
+    /// Play a DSP log recorded by --record-dsp without running the CPU
+    Replay(ReplayArgs),
+    /// Identify the sound driver of the song
+    Info(InfoArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct AuditionArgs {
+    /// Source number of the instrument in the sample directory
+    #[arg(long)]
+    srcn: u8,
+
+    /// Note to play, such as C4, F#3 or a MIDI note number
+    #[arg(long, default_value = "C4", value_parser = parse_note)]
+    note: i32,
+
+    /// Note played at the original pitch of the sample
+    #[arg(long, default_value = "C4", value_parser = parse_note)]
+    base: i32,
+
+    /// ADSR1 and ADSR2 in hex such as 8FE0
+    #[arg(long, value_parser = parse_adsr)]
+    adsr: Option<(u8, u8)>,
+
+    /// GAIN in hex, used instead of ADSR
+    #[arg(long, value_parser = parse_hex_u8, conflicts_with = "adsr")]
+    gain: Option<u8>,
+
+    /// Voice volume of both channels
+    #[arg(long, default_value_t = 127, allow_negative_numbers = true)]
+    volume: i8,
+
+    /// Key on length in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    length: u64,
+
+    /// Length in milliseconds rendered after key off
+    #[arg(long, default_value_t = 500)]
+    release: u64,
+
+    /// Render to a WAV file instead of playing
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Track index or name in an archive (.rsn, .zip, .spc2)
+    #[arg(short, long)]
+    track: Option<String>,
+
+    file: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct SynthArgs {
+    /// Instrument of a MIDI channel (1-16) as CH=SRCN or CH=SRCN,ADSR such as 1=3,8FE0.
+    /// Unmapped channels use their program number as SRCN
+    #[arg(long = "map", value_parser = parse_channel_map)]
+    maps: Vec<ChannelMap>,
+
+    /// Note played at the original pitch of samples
+    #[arg(long, default_value = "C4", value_parser = parse_note)]
+    base: i32,
+
+    /// Render to a WAV file instead of playing
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Track index or name in an archive (.rsn, .zip, .spc2)
+    #[arg(short, long)]
+    track: Option<String>,
+
+    file: String,
+
+    /// Standard MIDI file to play
+    midi: String,
+}
+
+// Instrument given to a MIDI channel by --map.
+#[derive(Clone, Copy, Debug)]
+struct ChannelMap {
+    // 0-based
+    channel: u8,
+    srcn: u8,
+    // ADSR1 and ADSR2
+    adsr: Option<(u8, u8)>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Render to a WAV file instead of playing
+    #[arg(short, long)]
+    output: Option<String>,
+
+    file: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// Track index or name in an archive (.rsn, .zip, .spc2)
+    #[arg(short, long)]
+    track: Option<String>,
 
     file: String,
 }
 
-fn main() -> Result<(), Error> {
+fn main() -> Result<(), Spc700Error> {
     let args = Args::parse(); 
+    match &args.action {
+        Some(Action::Audition(audition)) => return play_audition(audition),
+        Some(Action::Synth(synth)) => return play_synth(synth),
+        Some(Action::Replay(replay)) => return play_replay(replay),
+        Some(Action::Info(info)) => return print_info(info),
+        None => (),
+    }
+
+    let file = args.file.as_deref().unwrap_or_default();
+    let path = Path::new(file);
     let mut emulator = SPC700::new();
-    emulator.load(&Path::new(&args.file))?;
-    Amplifier::play(emulator, args.duration);
+
+    if args.list {
+        return list_tracks(path);
+    }
+
+    // index of the track if the file contains several tracks
+    let track_idx = load_track(&mut emulator, path, args.track.as_deref())?;
+    let sidecar = sidecar_path(file, track_idx);
+
+    emulator.set_tempo(args.tempo);
+    emulator.set_transpose(args.transpose, args.unlimited_pitch);
+    emulator.set_output_filter(args.filter);
+
+    let start = Duration::from_millis(args.start);
+    let mut end = start + Duration::from_millis(args.duration.unwrap_or(DEFAULT_DURATION_MS));
+    let mut fade = None;
+    let curve = match args.fade_curve {
+        Curve::Linear => FadeCurve::Linear,
+        Curve::Log => FadeCurve::Logarithmic,
+    };
+    if let Some(loops) = args.loops {
+        match emulator.detect_song_end(&SongEndOptions::default())? {
+            Some(song_end) => {
+                report_song_end(&song_end);
+                let fade_out = fade_after(&song_end, loops, args.fade, curve);
+                end = fade_out.start + fade_out.length;
+                fade = Some(fade_out);
+            }
+            None => eprintln!("neither loop nor silence is detected"),
+        }
+    } else if args.duration.is_none() && is_whole_song_required(&args) {
+        let fade_out = whole_song(&mut emulator, args.fade, curve)?;
+        end = fade_out.start + fade_out.length;
+        fade = Some(fade_out);
+    }
+
+    if args.analyze || args.write_loudness.is_some() {
+        emulator.set_post_mix(PostMix { fade, ..PostMix::default() });
+        let loudness = emulator.analyze_loudness(end)?;
+        print!("{}", loudness.to_sidecar());
+
+        match args.write_loudness {
+            Some(LoudnessTarget::Xid6) => {
+                let tagged = loudness.tag_spc(&fs::read(path)?)?;
+                fs::write(path, tagged)?;
+            }
+            Some(LoudnessTarget::Sidecar) => loudness.save_sidecar(&sidecar)?,
+            None => (),
+        }
+
+        return Ok(());
+    }
+
+    if let Some(midi) = &args.midi {
+        let options = MidiOptions { base_notes: args.midi_base.iter().copied().collect(), ..MidiOptions::default() };
+        fs::write(midi, emulator.export_midi(end, &options)?)?;
+        return Ok(());
+    }
+
+    if let Some(xm) = &args.xm {
+        fs::write(xm, emulator.export_xm(end)?)?;
+        return Ok(());
+    }
+
+    if let Some(sf2) = &args.sf2 {
+        fs::write(sf2, emulator.export_sf2(end)?)?;
+        return Ok(());
+    }
+
+    if let Some(log) = &args.record_dsp {
+        fs::write(log, emulator.record_dsp_log(end)?.to_bytes())?;
+        return Ok(());
+    }
+
+    let gain_db = match args.gain {
+        Some(gain) => gain,
+        None => stored_gain(&sidecar, emulator.tag())? as f32,
+    };
+
+    emulator.set_post_mix(PostMix { gain_db, soft_clip: args.soft_clip, fade });
+    emulator.seek(start);
+
+    match &args.output {
+        Some(output) => render_to_wav(emulator, Path::new(output), end)?,
+        None => Amplifier::play(emulator, end),
+    }
+
+    Ok(())
+}
+
+fn list_tracks(path: &Path) -> Result<(), Spc700Error> {
+    match Spc2File::open(path) {
+        Ok(spc2) => {
+            for (idx, song) in spc2.songs().iter().enumerate() {
+                println!("{:3}: {} - {}", idx, song.tag.song_title, song.tag.game_title);
+            }
+        }
+        Err(Spc700Error::BadHeader(_)) => {
+            for (idx, track) in SpcArchive::open(path)?.tracks().iter().enumerate() {
+                println!("{:3}: {}", idx, track.name);
+            }
+        }
+        Err(err) => return Err(err),
+    }
+
+    Ok(())
+}
+
+// Returns index of the loaded track if the file contains several tracks.
+fn load_track(emulator: &mut SPC700, path: &Path, key: Option<&str>) -> Result<Option<usize>, Spc700Error> {
+    match Spc2File::open(path) {
+        Ok(spc2) => {
+            let idx = match key {
+                Some(key) => select_spc2_song(&spc2, key).ok_or_else(|| track_not_found(key))?,
+                None => 0,
+            };
+            emulator.load_from_spc2(&spc2, idx)?;
+            Ok(Some(idx))
+        }
+        Err(Spc700Error::BadHeader(_)) => {
+            let archive = SpcArchive::open(path)?;
+            let track = match key {
+                Some(key) => archive.select(key).ok_or_else(|| track_not_found(key))?,
+                None => &archive.tracks()[0],
+            };
+            emulator.load_from_bytes(&track.data)?;
+
+            let idx = archive.tracks().iter().position(|entry| std::ptr::eq(entry, track));
+            Ok(idx.filter(|_| archive.tracks().len() > 1))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Keys on the instrument for `length`, and renders its release after key off.
+fn play_audition(args: &AuditionArgs) -> Result<(), Spc700Error> {
+    let mut emulator = SPC700::new();
+    load_track(&mut emulator, Path::new(&args.file), args.track.as_deref())?;
+
+    let pitch = 0x1000 as f32 * 2f32.powf((args.note - args.base) as f32 / 12.0);
+    let mut voice = emulator.audition();
+    voice.set_srcn(args.srcn);
+    voice.set_pitch(pitch.round().clamp(0.0, 0x3FFF as f32) as u16);
+    voice.set_volume(args.volume, args.volume);
+    match (args.adsr, args.gain) {
+        (Some((adsr1, adsr2)), _) => voice.set_adsr(adsr1, adsr2),
+        (None, Some(gain)) => {
+            voice.set_adsr(0, 0);
+            voice.set_gain(gain);
+        }
+        (None, None) => (),
+    }
+
+    let mut samples = vec![0; (duration_to_samples(Duration::from_millis(args.length)) * 2) as usize];
+    voice.key_on();
+    voice.render(&mut samples);
+    voice.key_off();
+    let mut release = vec![0; (duration_to_samples(Duration::from_millis(args.release)) * 2) as usize];
+    voice.render(&mut release);
+    samples.extend_from_slice(&release);
+
+    match &args.output {
+        Some(output) => write_wav(Path::new(output), &samples),
+        None => {
+            Amplifier::play_samples(&samples);
+            Ok(())
+        }
+    }
+}
+
+fn play_synth(args: &SynthArgs) -> Result<(), Spc700Error> {
+    let mut emulator = SPC700::new();
+    load_track(&mut emulator, Path::new(&args.file), args.track.as_deref())?;
+
+    let midi = fs::read(&args.midi)?;
+    let mut synth = emulator.synth(&midi)?;
+    synth.set_default_base_note(args.base as f32);
+    for map in args.maps.iter() {
+        let mut instrument = MidiInstrument { base_note: args.base as f32, ..MidiInstrument::new(map.srcn) };
+        if let Some((adsr1, adsr2)) = map.adsr {
+            instrument.adsr1 = adsr1;
+            instrument.adsr2 = adsr2;
+        }
+        synth.set_instrument(map.channel, Some(instrument));
+    }
+
+    // released notes are rendered until they become silent, but not longer than the tail
+    let max_samples = duration_to_samples(synth.length() + SYNTH_TAIL) as usize * 2;
+    let mut samples = Vec::new();
+    let mut buffer = [0; 1024];
+    while samples.len() < max_samples && !synth.is_finished() {
+        synth.render(&mut buffer);
+        samples.extend_from_slice(&buffer);
+    }
+
+    match &args.output {
+        Some(output) => write_wav(Path::new(output), &samples),
+        None => {
+            Amplifier::play_samples(&samples);
+            Ok(())
+        }
+    }
+}
+
+fn play_replay(args: &ReplayArgs) -> Result<(), Spc700Error> {
+    let log = DspLog::from_bytes(&fs::read(&args.file)?)?;
+    let mut replay = DspReplay::new(&log);
+
+    let mut samples = vec![0; log.samples as usize * 2];
+    replay.render(&mut samples);
+
+    match &args.output {
+        Some(output) => write_wav(Path::new(output), &samples),
+        None => {
+            Amplifier::play_samples(&samples);
+            Ok(())
+        }
+    }
+}
+
+fn print_info(args: &InfoArgs) -> Result<(), Spc700Error> {
+    let mut emulator = SPC700::new();
+    load_track(&mut emulator, Path::new(&args.file), args.track.as_deref())?;
+
+    let driver = match emulator.sound_driver()? {
+        Some(driver) => driver,
+        None => {
+            println!("driver: unknown");
+            return Ok(());
+        }
+    };
+
+    let addr = |addr: Option<u16>| addr.map(|addr| format!("${:04X}", addr)).unwrap_or_else(|| "unknown".to_string());
+    match driver.version {
+        Some(version) => println!("driver: {} ({})", driver.name, version),
+        None => println!("driver: {}", driver.name),
+    }
+    println!("song table: {}", addr(driver.song_table_addr));
+    match driver.instrument_size {
+        Some(size) => println!("instrument table: {} ({} bytes each)", addr(driver.instrument_table_addr), size),
+        None => println!("instrument table: {}", addr(driver.instrument_table_addr)),
+    }
+    println!("sequence: {}", addr(driver.sequence_addr));
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Analysis and export cover the whole song unless its length is given.
+fn is_whole_song_required(args: &Args) -> bool {
+    args.analyze || args.write_loudness.is_some() || args.midi.is_some() || args.xm.is_some()
+        || args.sf2.is_some()
+}
+
+// Fade out of the whole song by ID666 length and fade, or by detected song end played once.
+fn whole_song(emulator: &mut SPC700, fade_ms: u64, curve: FadeCurve) -> Result<FadeOut, Spc700Error> {
+    if let Some(tag) = emulator.tag().filter(|tag| tag.length_secs > 0) {
+        let start = Duration::from_secs(tag.length_secs as u64);
+        let length = Duration::from_millis(tag.fade_ms as u64);
+        return Ok(FadeOut { start, length, curve });
+    }
+
+    match emulator.detect_song_end(&SongEndOptions::default())? {
+        Some(song_end) => {
+            report_song_end(&song_end);
+            Ok(fade_after(&song_end, 1, fade_ms, curve))
+        }
+        None => Err(Spc700Error::InvalidState("song length is unknown, give it by --duration or --loops".to_string())),
+    }
+}
+
+// Silence ends immediately, and loop is faded out by `fade_ms`.
+fn fade_after(song_end: &SongEnd, loops: u32, fade_ms: u64, curve: FadeCurve) -> FadeOut {
+    let length = match song_end {
+        SongEnd::Silence { .. } => Duration::ZERO,
+        SongEnd::Loop { .. } => Duration::from_millis(fade_ms),
+    };
+
+    FadeOut { start: song_end.end_position(loops), length, curve }
+}
+
+// Sidecar of a track in a multi-track file is distinguished by the track index.
+fn sidecar_path(file: &str, track_idx: Option<usize>) -> PathBuf {
+    match track_idx {
+        Some(idx) => PathBuf::from(format!("{}.{}.replaygain", file, idx)),
+        None => PathBuf::from(format!("{}.replaygain", file)),
+    }
+}
+
+// ReplayGain in sidecar takes priority over xid6 amplification.
+fn stored_gain(sidecar: &Path, tag: Option<&Id666>) -> Result<f64, Spc700Error> {
+    if sidecar.exists() {
+        if let Some(gain) = read_sidecar_gain(sidecar)? {
+            return Ok(gain);
+        }
+    }
+
+    Ok(tag.and_then(|tag| tag.gain_db()).unwrap_or(0.0))
+}
+
+fn report_song_end(song_end: &SongEnd) {
+    match song_end {
+        SongEnd::Silence { at } => eprintln!("song ends at {:.3}s", at.as_secs_f64()),
+        SongEnd::Loop { intro, length } => eprintln!("intro: {:.3}s, loop: {:.3}s", intro.as_secs_f64(), length.as_secs_f64()),
+    }
+}
+
+const WAV_SPEC: hound::WavSpec = hound::WavSpec {
+    channels: 2,
+    sample_rate: SAMPLE_RATE,
+    bits_per_sample: 16,
+    sample_format: hound::SampleFormat::Int,
+};
+
+fn wav_error(err: hound::Error) -> Spc700Error {
+    Spc700Error::Io(io::Error::other(err))
+}
+
+fn write_wav(path: &Path, samples: &[i16]) -> Result<(), Spc700Error> {
+    let mut writer = hound::WavWriter::create(path, WAV_SPEC).map_err(wav_error)?;
+    for &sample in samples {
+        writer.write_sample(sample).map_err(wav_error)?;
+    }
+
+    writer.finalize().map_err(wav_error)
+}
+
+fn render_to_wav(mut core: SPC700, path: &Path, end: Duration) -> Result<(), Spc700Error> {
+    let mut writer = hound::WavWriter::create(path, WAV_SPEC).map_err(wav_error)?;
+
+    let mut remaining = duration_to_samples(end).saturating_sub(duration_to_samples(core.position())) as usize;
+    let mut block = [0; BLOCK_FRAMES * 2];
+    while remaining > 0 {
+        let frames = remaining.min(BLOCK_FRAMES);
+        core.render(&mut block[..frames * 2]);
+        for &sample in &block[..frames * 2] {
+            writer.write_sample(sample).map_err(wav_error)?;
+        }
+
+        if let Some(err) = core.fault() {
+            eprintln!("{}", err);
+            break;
+        }
+        remaining -= frames;
+    }
+
+    writer.finalize().map_err(wav_error)
+}
+
+fn parse_base_note(arg: &str) -> Result<(u8, f32), String> {
+    let (srcn, note) = arg.split_once('=').ok_or_else(|| format!("{} is not SRCN=NOTE", arg))?;
+    let srcn = srcn.trim().parse().map_err(|_| format!("invalid SRCN: {}", srcn))?;
+    let note = note.trim().parse().map_err(|_| format!("invalid note: {}", note))?;
+
+    Ok((srcn, note))
+}
+
+// MIDI note number from a name such as C4 (60), F#3 or Bb2, or the number itself.
+fn parse_note(arg: &str) -> Result<i32, String> {
+    if let Ok(number) = arg.parse::<i32>() {
+        return Ok(number);
+    }
+
+    let invalid = || format!("invalid note: {}", arg);
+    let mut chars = arg.chars();
+    let mut semitone = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(invalid()),
+    };
+    let rest = chars.as_str();
+    let octave = rest.trim_start_matches(['#', 'b']);
+    for accidental in rest[..rest.len() - octave.len()].chars() {
+        semitone += if accidental == '#' { 1 } else { -1 };
+    }
+    let octave = octave.parse::<i32>().map_err(|_| invalid())?;
+
+    Ok((octave + 1) * 12 + semitone)
+}
+
+// MIDI channel is 1-based in the argument, and 0-based in the result.
+fn parse_channel_map(arg: &str) -> Result<ChannelMap, String> {
+    let (channel, instrument) = arg.split_once('=').ok_or_else(|| format!("{} is not CH=SRCN[,ADSR]", arg))?;
+    let channel = match channel.trim().parse::<u8>() {
+        Ok(channel @ 1..=16) => channel - 1,
+        _ => return Err(format!("invalid channel: {}", channel)),
+    };
+    let (srcn, adsr) = match instrument.split_once(',') {
+        Some((srcn, adsr)) => (srcn, Some(parse_adsr(adsr.trim())?)),
+        None => (instrument, None),
+    };
+    let srcn = srcn.trim().parse().map_err(|_| format!("invalid SRCN: {}", srcn))?;
+
+    Ok(ChannelMap { channel, srcn, adsr })
+}
+
+fn parse_hex_u8(arg: &str) -> Result<u8, String> {
+    u8::from_str_radix(arg.trim_start_matches("0x"), 16).map_err(|_| format!("invalid hex: {}", arg))
+}
+
+// ADSR1 and ADSR2 written in this order
+fn parse_adsr(arg: &str) -> Result<(u8, u8), String> {
+    let value = u16::from_str_radix(arg.trim_start_matches("0x"), 16).map_err(|_| format!("invalid ADSR: {}", arg))?;
+    Ok(((value >> 8) as u8, value as u8))
+}
+
+fn select_spc2_song(spc2: &Spc2File, key: &str) -> Option<usize> {
+    match key.parse::<usize>() {
+        Ok(idx) if idx < spc2.songs().len() => Some(idx),
+        Ok(_) => None,
+        Err(_) => spc2.songs().iter().position(|song| song.tag.song_title.eq_ignore_ascii_case(key)),
+    }
+}
+
+fn track_not_found(key: &str) -> Spc700Error {
+    Spc700Error::InvalidState(format!("track {} is not found", key))
+}
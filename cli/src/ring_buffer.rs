@@ -0,0 +1,211 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Lock-free single producer single consumer ring buffer.
+// `head` and `tail` are monotonically increasing positions and wrapped by capacity when indexing.
+//
+// Invariants:
+//   - `tail - head` never exceeds capacity.
+//   - Only `Producer` stores `tail`, and only `Consumer` stores `head`. Both are not Clone,
+//     so there is at most one of each.
+//   - Slots in [head, tail) are owned by the consumer and the others by the producer.
+//     Producer publishes written slots by storing `tail` with Release after writing them,
+//     and consumer releases read slots by storing `head` with Release after reading them.
+//     Loading the other side's position with Acquire makes these accesses visible.
+struct RingBuffer<T> {
+  buffer: Box<[UnsafeCell<T>]>,
+  head: AtomicUsize,
+  tail: AtomicUsize,
+}
+
+// SAFETY: slots are only accessed through `Producer` and `Consumer`, which never touch the same slot
+// at the same time by the invariants above, and positions are atomics.
+unsafe impl<T: Send> Sync for RingBuffer<T> {}
+
+pub struct Producer<T> {
+  ring: Arc<RingBuffer<T>>,
+}
+
+pub struct Consumer<T> {
+  ring: Arc<RingBuffer<T>>,
+}
+
+pub fn ring_buffer<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+  let buffer = (0..capacity).map(|_| UnsafeCell::new(T::default())).collect();
+  let ring = Arc::new(RingBuffer {
+    buffer,
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+  });
+
+  (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+impl<T: Copy> Producer<T> {
+  pub fn free_len(&self) -> usize {
+    let head = self.ring.head.load(Ordering::Acquire);
+    let tail = self.ring.tail.load(Ordering::Relaxed);
+    self.ring.buffer.len() - tail.wrapping_sub(head)
+  }
+
+  // Returns the number of pushed elements.
+  pub fn push_slice(&mut self, data: &[T]) -> usize {
+    let capacity = self.ring.buffer.len();
+    let tail = self.ring.tail.load(Ordering::Relaxed);
+    let len = data.len().min(self.free_len());
+
+    for (offset, &value) in data[..len].iter().enumerate() {
+      let idx = tail.wrapping_add(offset) % capacity;
+      // SAFETY: `len` is at most the free length, so the slot is in [tail, head + capacity)
+      // and the consumer does not read it until `tail` is stored below.
+      unsafe { *self.ring.buffer[idx].get() = value; }
+    }
+
+    self.ring.tail.store(tail.wrapping_add(len), Ordering::Release);
+    len
+  }
+
+  // True if consumer is already dropped.
+  pub fn is_abandoned(&self) -> bool {
+    Arc::strong_count(&self.ring) == 1
+  }
+}
+
+impl<T: Copy> Consumer<T> {
+  pub fn available(&self) -> usize {
+    let head = self.ring.head.load(Ordering::Relaxed);
+    let tail = self.ring.tail.load(Ordering::Acquire);
+    tail.wrapping_sub(head)
+  }
+
+  // Returns the number of popped elements.
+  pub fn pop_slice(&mut self, data: &mut [T]) -> usize {
+    let capacity = self.ring.buffer.len();
+    let head = self.ring.head.load(Ordering::Relaxed);
+    let len = data.len().min(self.available());
+
+    for (offset, value) in data[..len].iter_mut().enumerate() {
+      let idx = head.wrapping_add(offset) % capacity;
+      // SAFETY: `len` is at most the available length, so the slot is in [head, tail)
+      // and the producer does not write it until `head` is stored below.
+      *value = unsafe { *self.ring.buffer[idx].get() };
+    }
+
+    self.ring.head.store(head.wrapping_add(len), Ordering::Release);
+    len
+  }
+
+  // Pops like `pop_slice`, and fills the rest of `data` by `silence` on underrun.
+  // Returns the number of popped elements.
+  pub fn pop_or_fill(&mut self, data: &mut [T], silence: T) -> usize {
+    let len = self.pop_slice(data);
+    data[len..].fill(silence);
+    len
+  }
+
+  // True if producer is already dropped. Remaining elements can still be popped.
+  pub fn is_abandoned(&self) -> bool {
+    Arc::strong_count(&self.ring) == 1
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+
+  #[test]
+  fn wraps_around_capacity() {
+    let (mut producer, mut consumer) = ring_buffer::<u32>(4);
+    let mut out = [0; 4];
+    for round in 0..5 {
+      let data = [round * 3, round * 3 + 1, round * 3 + 2];
+      assert_eq!(producer.push_slice(&data), 3);
+      assert_eq!(consumer.pop_slice(&mut out[..3]), 3);
+      assert_eq!(out[..3], data);
+    }
+  }
+
+  #[test]
+  fn push_stops_at_capacity() {
+    let (mut producer, mut consumer) = ring_buffer::<u32>(4);
+    assert_eq!(producer.push_slice(&[1, 2, 3, 4, 5, 6]), 4);
+    assert_eq!(producer.free_len(), 0);
+    assert_eq!(producer.push_slice(&[7]), 0);
+
+    let mut out = [0; 6];
+    assert_eq!(consumer.pop_slice(&mut out), 4);
+    assert_eq!(out[..4], [1, 2, 3, 4]);
+    assert_eq!(producer.free_len(), 4);
+  }
+
+  #[test]
+  fn underrun_is_filled_by_silence() {
+    let (mut producer, mut consumer) = ring_buffer::<i16>(8);
+    producer.push_slice(&[1, 2, 3]);
+
+    let mut out = [-1; 6];
+    assert_eq!(consumer.pop_or_fill(&mut out, 0), 3);
+    assert_eq!(out, [1, 2, 3, 0, 0, 0]);
+    assert_eq!(consumer.pop_or_fill(&mut out, 0), 0);
+    assert_eq!(out, [0; 6]);
+  }
+
+  #[test]
+  fn dropped_side_is_detected() {
+    let (mut producer, consumer) = ring_buffer::<i16>(8);
+    assert!(!producer.is_abandoned());
+    drop(consumer);
+    assert!(producer.is_abandoned());
+    // pushing after that is harmless
+    assert_eq!(producer.push_slice(&[1, 2]), 2);
+
+    let (mut producer, mut consumer) = ring_buffer::<i16>(8);
+    producer.push_slice(&[1, 2]);
+    assert!(!consumer.is_abandoned());
+    drop(producer);
+    assert!(consumer.is_abandoned());
+
+    let mut out = [0; 2];
+    assert_eq!(consumer.pop_slice(&mut out), 2);
+    assert_eq!(out, [1, 2]);
+  }
+
+  #[test]
+  fn keeps_order_across_threads() {
+    const COUNT: u32 = 200_000;
+    let (mut producer, mut consumer) = ring_buffer::<u32>(64);
+
+    let producer_thread = thread::spawn(move || {
+      let mut next = 0;
+      while next < COUNT {
+        // blocks of varying length, so pushes often wrap and stop at capacity
+        let len = (next % 37 + 1).min(COUNT - next);
+        let block: Vec<u32> = (next..next + len).collect();
+        let mut pushed = 0;
+        while pushed < block.len() {
+          pushed += producer.push_slice(&block[pushed..]);
+          thread::yield_now();
+        }
+        next += len;
+      }
+    });
+
+    let mut expected = 0;
+    let mut out = [0; 29];
+    while expected < COUNT {
+      let popped = consumer.pop_slice(&mut out);
+      for &value in out[..popped].iter() {
+        assert_eq!(value, expected);
+        expected += 1;
+      }
+      if popped == 0 {
+        thread::yield_now();
+      }
+    }
+
+    producer_thread.join().unwrap();
+    assert_eq!(consumer.available(), 0);
+  }
+}
@@ -0,0 +1,320 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::ops::Range;
+use std::path;
+
+use flate2::read::GzDecoder;
+
+use crate::error::{Result, Spc700Error};
+
+const ZIP_SIGNATURE: &[u8] = b"PK\x03\x04";
+const GZIP_SIGNATURE: &[u8] = &[0x1F, 0x8B];
+const RAR4_SIGNATURE: &[u8] = b"Rar!\x1A\x07\x00";
+const RAR5_SIGNATURE: &[u8] = b"Rar!\x1A\x07\x01\x00";
+
+pub struct ArchiveEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// Tracks contained in .rsn(RAR), .zip, gzip-compressed or plain .spc files.
+pub struct SpcArchive {
+    entries: Vec<ArchiveEntry>,
+}
+
+impl SpcArchive {
+    pub fn open(p: &path::Path) -> Result<SpcArchive> {
+        let bytes = fs::read(p)?;
+        let name = p.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        SpcArchive::from_bytes(&name, bytes)
+    }
+
+    // `name` is used as the entry name when `bytes` is not an archive.
+    pub fn from_bytes(name: &str, bytes: Vec<u8>) -> Result<SpcArchive> {
+        let entries =
+            if bytes.starts_with(ZIP_SIGNATURE) { read_zip(bytes)? }
+            else if bytes.starts_with(GZIP_SIGNATURE) { read_gzip(name, bytes)? }
+            else if bytes.starts_with(RAR4_SIGNATURE) { read_rar4(&bytes)? }
+            else if bytes.starts_with(RAR5_SIGNATURE) { read_rar5(&bytes)? }
+            else { vec![ArchiveEntry { name: name.to_string(), data: bytes }] };
+
+        let entries = entries.into_iter()
+            .filter(|entry| entry.data.starts_with(b"SNES-SPC700"))
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            return Err(Spc700Error::BadHeader("no spc file is found".to_string()));
+        }
+
+        Ok(SpcArchive { entries })
+    }
+
+    pub fn tracks(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&ArchiveEntry> {
+        self.entries.get(idx)
+    }
+
+    // Finds track by its index or its name.
+    // Name is compared case-insensitively, and with or without directory and extension.
+    pub fn select(&self, key: &str) -> Option<&ArchiveEntry> {
+        if let Ok(idx) = key.parse::<usize>() {
+            return self.get(idx);
+        }
+
+        let key = key.to_lowercase();
+        let stem = |name: &str| -> String {
+            let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+            let name = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name);
+            name.to_lowercase()
+        };
+
+        self.entries.iter()
+            .find(|entry| entry.name.to_lowercase() == key)
+            .or_else(|| self.entries.iter().find(|entry| stem(&entry.name) == stem(&key)))
+    }
+}
+
+fn read_zip(bytes: Vec<u8>) -> Result<Vec<ArchiveEntry>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(bytes))
+        .map_err(|err| Spc700Error::BadHeader(err.to_string()))?;
+
+    let mut entries = Vec::new();
+    for idx in 0..zip.len() {
+        let mut file = zip.by_index(idx).map_err(|err| Spc700Error::BadHeader(err.to_string()))?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        entries.push(ArchiveEntry { name: file.name().to_string(), data });
+    }
+
+    Ok(entries)
+}
+
+fn read_gzip(name: &str, bytes: Vec<u8>) -> Result<Vec<ArchiveEntry>> {
+    let mut decoder = GzDecoder::new(&bytes[..]);
+    let mut data = Vec::new();
+    decoder.read_to_end(&mut data)?;
+
+    let name = decoder.header()
+        .and_then(|header| header.filename())
+        .map(|filename| String::from_utf8_lossy(filename).into_owned())
+        .unwrap_or_else(|| name.trim_end_matches(".gz").to_string());
+
+    Ok(vec![ArchiveEntry { name, data }])
+}
+
+// RAR is only supported for stored(uncompressed) entries.
+fn unsupported_rar_entry(name: &str) -> Spc700Error {
+    Spc700Error::UnsupportedArchive(format!("{} is compressed or encrypted in RAR archive", name))
+}
+
+fn truncated_rar(expected: usize, actual: usize) -> Spc700Error {
+    Spc700Error::TruncatedFile { expected, actual }
+}
+
+fn read_rar4(bytes: &[u8]) -> Result<Vec<ArchiveEntry>> {
+    const MAIN_HEADER: u8 = 0x73;
+    const FILE_HEADER: u8 = 0x74;
+    const END_HEADER: u8 = 0x7B;
+    const METHOD_STORE: u8 = 0x30;
+
+    let u16_at = |pos: usize| (bytes[pos] as u16) | ((bytes[pos + 1] as u16) << 8);
+    let u32_at = |pos: usize| (u16_at(pos) as u32) | ((u16_at(pos + 2) as u32) << 16);
+
+    let mut entries = Vec::new();
+    let mut pos = RAR4_SIGNATURE.len();
+    while pos + 7 <= bytes.len() {
+        let head_type = bytes[pos + 2];
+        let head_flags = u16_at(pos + 3);
+        let head_size = u16_at(pos + 5) as usize;
+        let has_add_size = (head_flags & 0x8000) != 0 || head_type == FILE_HEADER;
+        if head_size < 7 || pos + head_size > bytes.len() || (has_add_size && head_size < 11) {
+            return Err(truncated_rar(pos + head_size.max(7), bytes.len()));
+        }
+
+        let add_size = if has_add_size { u32_at(pos + 7) as usize } else { 0 };
+
+        match head_type {
+            MAIN_HEADER if (head_flags & 0x0080) != 0 => return Err(unsupported_rar_entry("header")),
+            FILE_HEADER if head_size < 32 => return Err(truncated_rar(pos + 32, bytes.len())),
+            FILE_HEADER => {
+                let method = bytes[pos + 25];
+                let name_size = u16_at(pos + 26) as usize;
+                let name_offset = if (head_flags & 0x0100) != 0 { pos + 40 } else { pos + 32 };
+                let name = bytes.get(name_offset..name_offset + name_size)
+                    .map(|name| String::from_utf8_lossy(name.split(|&b| b == 0).next().unwrap_or(name)).into_owned())
+                    .ok_or_else(|| truncated_rar(name_offset + name_size, bytes.len()))?;
+
+                let is_dir = (head_flags & 0x00E0) == 0x00E0;
+                let is_encrypted = (head_flags & 0x0004) != 0;
+                let is_split = (head_flags & 0x0003) != 0;
+
+                if !is_dir {
+                    if method != METHOD_STORE || is_encrypted || is_split {
+                        return Err(unsupported_rar_entry(&name));
+                    }
+
+                    let data_start = pos + head_size;
+                    let data = bytes.get(data_start..data_start + add_size)
+                        .ok_or_else(|| truncated_rar(data_start + add_size, bytes.len()))?;
+                    entries.push(ArchiveEntry { name, data: data.to_vec() });
+                }
+            }
+            END_HEADER => break,
+            _ => (),
+        }
+
+        pos += head_size + add_size;
+    }
+
+    Ok(entries)
+}
+
+fn read_rar5(bytes: &[u8]) -> Result<Vec<ArchiveEntry>> {
+    const FILE_HEADER: u64 = 2;
+    const ENCRYPTION_HEADER: u64 = 4;
+    const END_HEADER: u64 = 5;
+
+    fn vint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+        let mut value = 0;
+        for shamt in (0..64).step_by(7) {
+            let byte = *bytes.get(*pos).ok_or_else(|| truncated_rar(*pos + 1, bytes.len()))?;
+            *pos += 1;
+            value |= ((byte & 0x7F) as u64) << shamt;
+            if (byte & 0x80) == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Spc700Error::BadHeader("invalid RAR variable length integer".to_string()))
+    }
+
+    // Extra area is a list of records, each of which is its size, its type and its data.
+    fn has_encryption_record(bytes: &[u8], extra: Range<usize>) -> Result<bool> {
+        const ENCRYPTION_RECORD: u64 = 1;
+
+        let mut pos = extra.start;
+        while pos < extra.end {
+            let record_size = vint(bytes, &mut pos)? as usize;
+            let record_end = pos + record_size;
+            if vint(bytes, &mut pos)? == ENCRYPTION_RECORD {
+                return Ok(true);
+            }
+            pos = record_end;
+        }
+
+        Ok(false)
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = RAR5_SIGNATURE.len();
+    while pos + 4 < bytes.len() {
+        // skip header CRC32
+        let mut cursor = pos + 4;
+        let head_size = vint(bytes, &mut cursor)? as usize;
+        let head_end = cursor + head_size;
+        if head_end > bytes.len() {
+            return Err(truncated_rar(head_end, bytes.len()));
+        }
+
+        let head_type = vint(bytes, &mut cursor)?;
+        let head_flags = vint(bytes, &mut cursor)?;
+        let extra_size = if (head_flags & 0x01) != 0 { vint(bytes, &mut cursor)? as usize } else { 0 };
+        let data_size = if (head_flags & 0x02) != 0 { vint(bytes, &mut cursor)? as usize } else { 0 };
+
+        match head_type {
+            ENCRYPTION_HEADER => return Err(unsupported_rar_entry("header")),
+            FILE_HEADER => {
+                let file_flags = vint(bytes, &mut cursor)?;
+                let _unpacked_size = vint(bytes, &mut cursor)?;
+                let _attributes = vint(bytes, &mut cursor)?;
+                if (file_flags & 0x02) != 0 { cursor += 4; } // mtime
+                if (file_flags & 0x04) != 0 { cursor += 4; } // CRC32
+                let compression = vint(bytes, &mut cursor)?;
+                let _host_os = vint(bytes, &mut cursor)?;
+                let name_size = vint(bytes, &mut cursor)? as usize;
+                let name = bytes.get(cursor..cursor + name_size)
+                    .map(|name| String::from_utf8_lossy(name).into_owned())
+                    .ok_or_else(|| truncated_rar(cursor + name_size, bytes.len()))?;
+
+                let is_dir = (file_flags & 0x01) != 0;
+                let method = (compression >> 7) & 0x07;
+                let is_split = (head_flags & 0x18) != 0;
+                let is_encrypted = has_encryption_record(bytes, head_end.saturating_sub(extra_size)..head_end)?;
+
+                if !is_dir {
+                    if method != 0 || is_encrypted || is_split {
+                        return Err(unsupported_rar_entry(&name));
+                    }
+
+                    let data = bytes.get(head_end..head_end + data_size)
+                        .ok_or_else(|| truncated_rar(head_end + data_size, bytes.len()))?;
+                    entries.push(ArchiveEntry { name, data: data.to_vec() });
+                }
+            }
+            END_HEADER => break,
+            _ => (),
+        }
+
+        pos = head_end + data_size;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fixtures are written from the RAR 4.x technote and the RAR 5.0 format description,
+    // and are listed and extracted by libarchive as well.
+    const STORED_RAR4: &[u8] = include_bytes!("../tests/fixtures/stored.rar");
+    const STORED_RAR5: &[u8] = include_bytes!("../tests/fixtures/stored_v5.rar");
+    const SPC_DATA: &[u8] = b"SNES-SPC700 Sound File Data v0.30\x1a\x1a\x1a\x1e";
+
+    #[test]
+    fn reads_stored_entries() {
+        for bytes in [STORED_RAR4, STORED_RAR5] {
+            let entries = if bytes.starts_with(RAR5_SIGNATURE) { read_rar5(bytes) } else { read_rar4(bytes) }.unwrap();
+            let entries = entries.iter().map(|entry| (entry.name.as_str(), entry.data.as_slice())).collect::<Vec<_>>();
+            assert_eq!(entries, [("song.spc", SPC_DATA), ("readme.txt", &b"stored entry\n"[..])]);
+
+            let archive = SpcArchive::from_bytes("songs.rsn", bytes.to_vec()).unwrap();
+            assert_eq!(archive.tracks().len(), 1);
+            assert_eq!(archive.select("song").unwrap().data, SPC_DATA);
+        }
+    }
+
+    #[test]
+    fn rejects_compressed_or_encrypted_entries() {
+        let fixtures: [&[u8]; 4] = [
+            include_bytes!("../tests/fixtures/compressed.rar"),
+            include_bytes!("../tests/fixtures/encrypted.rar"),
+            include_bytes!("../tests/fixtures/compressed_v5.rar"),
+            include_bytes!("../tests/fixtures/encrypted_v5.rar"),
+        ];
+
+        for bytes in fixtures {
+            let result = SpcArchive::from_bytes("songs.rsn", bytes.to_vec());
+            assert!(matches!(result, Err(Spc700Error::UnsupportedArchive(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        // both cut in the middle of the first file header
+        for bytes in [&STORED_RAR4[..30], &STORED_RAR5[..24]] {
+            let result = SpcArchive::from_bytes("songs.rsn", bytes.to_vec());
+            assert!(matches!(result, Err(Spc700Error::TruncatedFile { .. })));
+        }
+    }
+}
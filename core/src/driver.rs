@@ -0,0 +1,148 @@
+// Sound driver identification by code signatures in RAM.
+//
+// Signatures are written as hex bytes separated by spaces, and "??" matches any byte.
+// Each detector looks for code which reads tables of the driver, and takes table addresses
+// from its operands.
+//
+// N-SPC signatures follow the song and instrument loading code of the Super Mario World driver.
+// Other drivers are reported as unknown until their signatures are taken from real dumps,
+// since a signature made of common code idioms would identify a wrong driver.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoundDriver {
+    pub name: &'static str,
+    // None if the matched code does not tell the version
+    pub version: Option<&'static str>,
+    // entry of the first song
+    pub song_table_addr: Option<u16>,
+    pub instrument_table_addr: Option<u16>,
+    // bytes per entry of the instrument table
+    pub instrument_size: Option<u8>,
+    // sequence data of the first track of the first song
+    pub sequence_addr: Option<u16>,
+}
+
+type Detector = fn(&[u8]) -> Option<SoundDriver>;
+
+const DETECTORS: &[Detector] = &[detect_nspc];
+
+// Returns the first driver whose signature is found in `ram`.
+pub fn detect_sound_driver(ram: &[u8]) -> Option<SoundDriver> {
+    DETECTORS.iter().find_map(|detect| detect(ram))
+}
+
+// Song index in A is doubled into X, and the song is ignored if the upper byte of its pointer is 0.
+//   asl a / mov x,a / mov a,!table-1+x / mov y,a / bne / mov $04,a / ret / mov a,!table-2+x / movw $40,ya
+const NSPC_SONG_LIST: &str = "1c 5d f5 ?? ?? fd d0 03 c4 ?? 6f f5 ?? ?? da ??";
+// Offset of the instrument is added to the table address given as immediate values.
+//   mov y,#size / mul ya / movw $14,ya / clrc / adc $14,#lo / adc $15,#hi
+const NSPC_INSTRUMENT: &str = "8d ?? cf da ?? 60 98 ?? ?? 98 ?? ??";
+
+// Nintendo N-SPC used by many first and third party games.
+// Song table has pointers to pattern lists, whose entries point to 8 track pointers.
+fn detect_nspc(ram: &[u8]) -> Option<SoundDriver> {
+    // song numbers start at 1, and operand points to the entry of song 0
+    let song_table_addr = read_u16(ram, find(ram, NSPC_SONG_LIST)? + 12).wrapping_add(2);
+
+    let instrument = find(ram, NSPC_INSTRUMENT).map(|pos| {
+        let addr = u16::from_le_bytes([ram[pos + 7], ram[pos + 10]]);
+        (addr, ram[pos + 1])
+    });
+
+    let sequence_addr = non_zero(read_u16(ram, song_table_addr as usize))
+        .and_then(|patterns| non_zero(read_u16(ram, patterns as usize)))
+        .and_then(|tracks| first_track(ram, tracks));
+
+    Some(SoundDriver {
+        name: "Nintendo N-SPC",
+        version: None,
+        song_table_addr: Some(song_table_addr),
+        instrument_table_addr: instrument.map(|(addr, _)| addr),
+        instrument_size: instrument.map(|(_, size)| size),
+        sequence_addr,
+    })
+}
+
+// The first non-zero pointer of 8 tracks at `tracks`.
+fn first_track(ram: &[u8], tracks: u16) -> Option<u16> {
+    (0..8).map(|idx| read_u16(ram, tracks as usize + idx * 2)).find(|&addr| addr != 0)
+}
+
+// Returns the position of the first match of `signature`.
+fn find(ram: &[u8], signature: &str) -> Option<usize> {
+    let pattern: Vec<Option<u8>> = signature.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect();
+
+    ram.windows(pattern.len()).position(|window| {
+        window.iter().zip(pattern.iter()).all(|(&byte, expected)| expected.is_none_or(|expected| expected == byte))
+    })
+}
+
+fn read_u16(ram: &[u8], addr: usize) -> u16 {
+    let lower = ram.get(addr).copied().unwrap_or(0);
+    let upper = ram.get(addr + 1).copied().unwrap_or(0);
+    u16::from_le_bytes([lower, upper])
+}
+
+fn non_zero(value: u16) -> Option<u16> {
+    Some(value).filter(|&value| value != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE_ADDR: usize = 0x0800;
+
+    // RAM with `code` at 0x0800 and `data` at each address.
+    fn ram(code: &[u8], data: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut ram = vec![0; 0x10000];
+        ram[CODE_ADDR..CODE_ADDR + code.len()].copy_from_slice(code);
+        data.iter().for_each(|&(addr, bytes)| ram[addr..addr + bytes.len()].copy_from_slice(bytes));
+        ram
+    }
+
+    // 8 track pointers, whose first one is empty
+    const TRACKS: &[u8] = &[0x00, 0x00, 0x00, 0x30, 0x40, 0x30, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn detects_nspc() {
+        let code = [
+            // operands are the song table 0x2000 minus 1 and 2
+            0x1C, 0x5D, 0xF5, 0xFF, 0x1F, 0xFD, 0xD0, 0x03, 0xC4, 0x04, 0x6F, 0xF5, 0xFE, 0x1F, 0xDA, 0x40,
+            0x8D, 0x06, 0xCF, 0xDA, 0x14, 0x60, 0x98, 0x00, 0x14, 0x98, 0x3D, 0x15,
+        ];
+        // song 1 has a pattern list at 0x2100, whose first pattern has tracks at 0x2200
+        let ram = ram(&code, &[(0x2000, &[0x00, 0x21]), (0x2100, &[0x00, 0x22]), (0x2200, TRACKS)]);
+
+        assert_eq!(detect_sound_driver(&ram), Some(SoundDriver {
+            name: "Nintendo N-SPC",
+            version: None,
+            song_table_addr: Some(0x2000),
+            instrument_table_addr: Some(0x3D00),
+            instrument_size: Some(6),
+            sequence_addr: Some(0x3000),
+        }));
+    }
+
+    #[test]
+    fn unknown_driver() {
+        assert_eq!(detect_sound_driver(&ram(&[], &[])), None);
+        // song table lookup without the N-SPC branch stopping an empty song
+        let code = [0x1C, 0x5D, 0xF5, 0xFF, 0x1F, 0xFD, 0xF5, 0xFE, 0x1F, 0xDA, 0x40];
+        assert_eq!(detect_sound_driver(&ram(&code, &[])), None);
+        // a signature cut at the end of RAM
+        let mut ram = vec![0; 0x10000];
+        ram[0xFFFC..].copy_from_slice(&[0x1C, 0x5D, 0xF5, 0xFF]);
+        assert_eq!(detect_sound_driver(&ram), None);
+    }
+
+    #[test]
+    fn wildcards_match_any_byte() {
+        assert_eq!(find(&[0x00, 0x1C, 0xAB, 0x5D], "1c ?? 5d"), Some(1));
+        assert_eq!(find(&[0x1C, 0xAB, 0x5E], "1c ?? 5d"), None);
+        assert_eq!(read_u16(&[0x34, 0x12], 0), 0x1234);
+        assert_eq!(read_u16(&[0x34], 0), 0x0034);
+    }
+}
@@ -0,0 +1,119 @@
+use super::DSP;
+use super::render_frames;
+use super::PitchShift;
+use super::CYCLE_RANGE;
+use super::block::DSPBlock;
+
+// A voice played directly from the host, independently of voices driven by the CPU.
+// Samples are read from the sample directory in the loaded RAM, but the CPU does not run.
+// Output is the voice output after its volume, without master volume and echo.
+pub struct Audition {
+    block: DSPBlock,
+    table_addr: u8,
+    counter: u16,
+}
+
+impl Audition {
+    // Uses the sample directory (DIR) of the loaded song.
+    // The voice has full volume and ADSR with the fastest attack and no decay until settings are changed.
+    pub fn new() -> Audition {
+        let mut block = DSPBlock::new();
+        block.reg.pitch = 0x1000;
+        block.reg.vol_left = 0x7F;
+        block.reg.vol_right = 0x7F;
+        block.reg.adsr = 0xE08F;
+
+        Audition { block, table_addr: DSP::global().table_addr, counter: 0 }
+    }
+
+    pub fn set_table_addr(&mut self, dir: u8) {
+        self.table_addr = dir;
+    }
+
+    // Takes effect at the next key on.
+    pub fn set_srcn(&mut self, srcn: u8) {
+        self.block.reg.srcn = srcn;
+    }
+
+    // 14bit pitch. 0x1000 plays the sample at the original rate.
+    pub fn set_pitch(&mut self, pitch: u16) {
+        self.block.reg.pitch = pitch & 0x3FFF;
+    }
+
+    // Envelope by ADSR registers. GAIN is used instead if bit 7 of `adsr1` is cleared.
+    pub fn set_adsr(&mut self, adsr1: u8, adsr2: u8) {
+        self.block.reg.adsr = ((adsr2 as u16) << 8) | adsr1 as u16;
+    }
+
+    // Used while ADSR is disabled.
+    pub fn set_gain(&mut self, gain: u8) {
+        self.block.reg.gain = gain;
+    }
+
+    pub fn set_volume(&mut self, left: i8, right: i8) {
+        self.block.reg.vol_left = left as u8;
+        self.block.reg.vol_right = right as u8;
+    }
+
+    pub fn key_on(&mut self) {
+        self.block.keyon(self.table_addr as u16);
+    }
+
+    // Envelope moves to release at the next sample.
+    pub fn key_off(&mut self) {
+        self.block.reg.key_off = true;
+    }
+
+    // Whether the voice still makes sound or waits for key on delay.
+    pub fn is_sounding(&self) -> bool {
+        self.block.key_on_delay > 0 || self.block.envelope.level > 0
+    }
+
+    pub fn next_sample(&mut self) -> (i16, i16) {
+        self.block.flush(None, false, self.counter, true, &PitchShift::new());
+        self.counter = (self.counter + 1) % CYCLE_RANGE;
+
+        (self.block.sample_left, self.block.sample_right)
+    }
+
+    // Fills `buffer` with interleaved stereo samples and returns the number of rendered frames.
+    pub fn render(&mut self, buffer: &mut [i16]) -> usize {
+        render_frames(buffer, || self.next_sample())
+    }
+}
+
+impl Default for Audition {
+    fn default() -> Audition {
+        Audition::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::Spc700;
+    use crate::testing::{lock_globals, spc_file, IDLE};
+
+    #[test]
+    fn keys_on_after_delay_and_releases() {
+        let _lock = lock_globals();
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(IDLE, &[])).unwrap();
+        let mut audition = emulator.audition();
+
+        audition.key_on();
+        let mut buffer = vec![0; 64 * 2];
+        audition.render(&mut buffer);
+        // key on delay of 5 samples
+        assert!(buffer[..5 * 2].iter().all(|&sample| sample == 0));
+        // the envelope starts its attack after the delay, and the first BRR points pass through interpolation
+        assert!(buffer[5 * 2..8 * 2].iter().any(|&sample| sample != 0));
+        assert!(audition.is_sounding());
+
+        // release lowers the envelope from 0x7FF by 8 every sample
+        audition.key_off();
+        let release = (0..0x800 / 8).map(|_| audition.next_sample()).collect::<Vec<_>>();
+        assert_ne!(release[0], (0, 0));
+        assert!(!audition.is_sounding());
+        assert!((0..64).all(|_| audition.next_sample() == (0, 0)));
+    }
+}
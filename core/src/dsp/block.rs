@@ -1,4 +1,5 @@
 use super::DSPRegister;
+use super::PitchShift;
 use super::brr::{BRRInfo, BRREnd};
 use super::envelope::{Envelope, ADSRMode};
 use super::gaussian_table;
@@ -63,18 +64,21 @@ impl DSPBlock {
         self.reg = DSPRegister::new_with_init(idx, regs);    
     }
 
-    pub fn flush(&mut self, before_out: Option<i16>, soft_reset: bool, cycle_counter: u16) -> () {                
+    // If `require_out` is false, interpolation is skipped and the voice outputs silence.
+    pub fn flush(&mut self, before_out: Option<i16>, soft_reset: bool, cycle_counter: u16, require_out: bool, pitch_shift: &PitchShift) -> () {                
         // fetch brr nibbles 
         let brr_info = &self.brr_info;
         
         // calculate related pitch
-        let step = generate_additional_pitch(&self.reg, before_out);
+        let step = generate_additional_pitch(&self.reg, before_out, pitch_shift);
         let (next_pitch, require_next_block) = self.pitch_counter.overflowing_add(step);        
         
         // filter sample
         let nibble_idx = ((self.pitch_counter >> 12) & 0x0F) as usize;
         let gaussian_idx = (self.pitch_counter >> 4) & 0xFF;
-        let sample = gaussian_interpolation(gaussian_idx as usize, &self.buffer[nibble_idx..(nibble_idx + 4)]);        
+        let sample = 
+            if require_out { gaussian_interpolation(gaussian_idx as usize, &self.buffer[nibble_idx..(nibble_idx + 4)]) }
+            else { 0 };
 
         // envelope        
         let is_brr_end = brr_info.end == BRREnd::Mute;        
@@ -123,11 +127,10 @@ impl DSPBlock {
             if self.is_loop {
                 self.src_addr = self.loop_addr;
             } else {
-                self.src_addr += 9;
+                self.src_addr = self.src_addr.wrapping_add(9);
             }
 
-            let addr = self.src_addr as usize;                
-            let brr_block = &Ram::global().ram[addr..addr + 9];                
+            let brr_block = fetch_brr_block(self.src_addr);
 
             self.brr_info = BRRInfo::new(brr_block[0]);                
             generate_new_sample(&brr_block[1..], &mut self.buffer, &self.brr_info);
@@ -158,11 +161,11 @@ impl DSPBlock {
         self.envelope.adsr_mode = ADSRMode::Attack;
         self.envelope.level = 0;
 
-        let table_addr = table_addr * 256 + (self.reg.srcn as u16 * 4);
+        let table_addr = table_addr.wrapping_mul(256).wrapping_add(self.reg.srcn as u16 * 4);
         let start0 = Ram::global().read_ram(table_addr) as u16;
-        let start1 = Ram::global().read_ram(table_addr + 1) as u16;
-        let loop0 = Ram::global().read_ram(table_addr + 2) as u16;
-        let loop1 = Ram::global().read_ram(table_addr + 3) as u16;
+        let start1 = Ram::global().read_ram(table_addr.wrapping_add(1)) as u16;
+        let loop0 = Ram::global().read_ram(table_addr.wrapping_add(2)) as u16;
+        let loop1 = Ram::global().read_ram(table_addr.wrapping_add(3)) as u16;
 
         self.pitch_counter = 0x0000;
  
@@ -173,18 +176,28 @@ impl DSPBlock {
         self.src_addr = self.start_addr;
         self.key_on_delay = 5;
 
-        let addr = self.src_addr as usize;                
-        let brr_block = &Ram::global().ram[addr..addr + 9];                
+        let brr_block = fetch_brr_block(self.src_addr);
 
         self.brr_info = BRRInfo::new(brr_block[0]);                
         generate_new_sample(&brr_block[1..], &mut self.buffer, &self.brr_info);
     }
 }
 
-fn generate_additional_pitch(reg: &DSPRegister, before_out: Option<i16>) -> u16 {
+// BRR block may straddle the end of RAM, so the address wraps around.
+pub(super) fn fetch_brr_block(addr: u16) -> [u8; 9] {
+    let mut block = [0; 9];
+    block.iter_mut().zip(0..).for_each(|(b, offset)| {
+        *b = Ram::global().read_ram(addr.wrapping_add(offset));
+    });
+
+    block
+}
+
+// Pitch shift is applied after pitch modulation, so register values are kept as they are.
+fn generate_additional_pitch(reg: &DSPRegister, before_out: Option<i16>, pitch_shift: &PitchShift) -> u16 {
     let base_step = reg.pitch & 0x3FFF;
     
-    if !reg.pmon_enable || before_out.is_none() {
+    let step = if !reg.pmon_enable || before_out.is_none() {
         base_step
     } else {        
         let factor = before_out.unwrap();
@@ -195,7 +208,9 @@ fn generate_additional_pitch(reg: &DSPRegister, before_out: Option<i16>) -> u16
         if ret > 0x3FFF { 0x3FFF }
         else if ret < 0 { 0 }
         else            { ret as u16 }
-    }
+    };
+
+    pitch_shift.apply(step)
 }
 
 fn gaussian_interpolation(base_idx: usize, buffer: &[i16]) -> i16 {       
@@ -215,7 +230,7 @@ fn gaussian_interpolation(base_idx: usize, buffer: &[i16]) -> i16 {
     out as i16
 }
 
-fn generate_new_sample(brrs: &[u8], buffer: &mut [i16; SAMPLE_BUFFER_SIZE], brr_info: &BRRInfo) -> () {    
+pub(super) fn generate_new_sample(brrs: &[u8], buffer: &mut [i16; SAMPLE_BUFFER_SIZE], brr_info: &BRRInfo) -> () {    
     fn no_filter(sample: i32, _old: i32, _older: i32) -> i32 {
         sample
     }
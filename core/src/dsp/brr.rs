@@ -27,15 +27,13 @@ impl BRRInfo {
             0 => FilterType::NoFilter,
             1 => FilterType::UseOld,
             2 => FilterType::UseAll0,
-            3 => FilterType::UseAll1,
-            _ => panic!("filter value should be between 0 to 3"),
+            _ => FilterType::UseAll1, // 3
         };
 
         let end = match format & 0b11 {
-            0 | 2 => BRREnd::Normal,
             1     => BRREnd::Mute,
             3     => BRREnd::Loop,
-            _     => panic!("end range should be between 0 to 3"),
+            _     => BRREnd::Normal, // 0 or 2
         };
 
         BRRInfo {
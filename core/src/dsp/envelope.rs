@@ -30,7 +30,7 @@ const COUNTER_OFFSETS: [u16; 32] = [
          0
 ];
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum ADSRMode {
     Attack,
     Decay,
@@ -98,6 +98,11 @@ impl Envelope {
     }    
 }
 
+// Samples between envelope steps of `rate` (0..32). None if the envelope never changes.
+pub(crate) fn rate_period(rate: usize) -> Option<u16> {
+    if rate == 0 { None } else { Some(ADSR_GAIN_RATES[rate]) }
+}
+
 fn update_envelope_with_adsr(env: &Envelope, reg: &DSPRegister) -> (Option<usize>, i16) {
     let (rate, step) = match env.adsr_mode {
         ADSRMode::Attack => {
@@ -162,8 +167,7 @@ fn get_gain_mode(flag: u8) -> GainMode {
         0 => GainMode::LinearDecrease,
         1 => GainMode::ExpDecrease,
         2 => GainMode::LinearIncrease,
-        3 => GainMode::BentIncrease,
-        _ => panic!("gain mode value must be between 0 to 3"),
+        _ => GainMode::BentIncrease, // 3
     }
 }
 
@@ -0,0 +1,170 @@
+// Final mixing stage of DSP.
+//
+//   voice outputs ---+--> main accumulator (clamped at each voice) --> * MVOL --+
+//                    |                                                          +--> clamp --> FLG mute --> invert --> DAC
+//   echo buffer --> FIR --> echo input ---------------------------------> * EVOL --+
+//                    |                         |
+//                    +--> echo accumulator ----+--> + echo input * EFB --> clamp --> echo buffer
+//
+// Products with volumes are truncated into 16 bits without clamping as the real DSP does.
+use super::DSP;
+use crate::processor::ram::Ram;
+
+pub(super) fn mix(dsp: &mut DSP) -> (i16, i16) {
+    let (main_left, main_right) = accumulate(dsp.blocks.iter().map(|blk| (blk.sample_left, blk.sample_right)));
+    let (echo_in_left, echo_in_right) = process_echo(dsp);
+
+    let left = output(main_left, echo_in_left, dsp.master_vol_left, dsp.echo_vol_left);
+    let right = output(main_right, echo_in_right, dsp.master_vol_right, dsp.echo_vol_right);
+
+    (to_dac(left, dsp.is_mute), to_dac(right, dsp.is_mute))
+}
+
+// Runs only echo path, so FIR history and echo buffer in RAM stay the same as `mix`.
+// Main accumulation and output are skipped.
+pub(super) fn skip(dsp: &mut DSP) {
+    process_echo(dsp);
+}
+
+// Reads echo buffer through FIR, writes echoed voices with feedback back into it,
+// and returns echo input.
+fn process_echo(dsp: &mut DSP) -> (i16, i16) {
+    let (echo_left, echo_right) = accumulate(dsp.blocks.iter().map(|blk| (blk.echo_left, blk.echo_right)));
+
+    let buffer_addr = dsp.echo_ring_buffer_addr.wrapping_add(dsp.echo_pos);
+    let echo_in_left = dsp.fir_left.next(read_echo(buffer_addr));
+    let echo_in_right = dsp.fir_right.next(read_echo(buffer_addr.wrapping_add(2)));
+
+    if dsp.echo_buffer_enable {
+        let feedback = dsp.echo_feedback_volume;
+        write_echo(buffer_addr, echo_feedback(echo_left, echo_in_left, feedback));
+        write_echo(buffer_addr.wrapping_add(2), echo_feedback(echo_right, echo_in_right, feedback));
+    }
+    advance_echo_pos(dsp);
+
+    (echo_in_left, echo_in_right)
+}
+
+fn advance_echo_pos(dsp: &mut DSP) {
+    dsp.echo_pos += 4;
+    if dsp.echo_pos >= dsp.echo_buf_length {
+        dsp.echo_pos = 0;
+    }
+}
+
+// echo buffer wraps around at the end of RAM
+fn read_echo(addr: u16) -> i16 {
+    let ram = Ram::global();
+    let lower = ram.read_ram(addr) as u16;
+    let upper = ram.read_ram(addr.wrapping_add(1)) as u16;
+
+    (lower | (upper << 8)) as i16
+}
+
+fn write_echo(addr: u16, sample: i16) {
+    let ram = &mut Ram::global().ram;
+    ram[addr as usize] = sample as u8;
+    ram[addr.wrapping_add(1) as usize] = (sample >> 8) as u8;
+}
+
+fn clamp16(value: i32) -> i16 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+// Voice outputs are added one by one, and clamped at each addition.
+fn accumulate(samples: impl Iterator<Item = (i16, i16)>) -> (i16, i16) {
+    samples.fold((0, 0), |(left, right), (sample_left, sample_right)| {
+        (clamp16(left as i32 + sample_left as i32), clamp16(right as i32 + sample_right as i32))
+    })
+}
+
+fn apply_volume(sample: i16, volume: u8) -> i16 {
+    ((sample as i32 * (volume as i8) as i32) >> 7) as i16
+}
+
+// `history` is halved echo buffer samples from oldest to newest.
+// Sum of the first 7 taps wraps into 16 bits, and only the last tap is clamped.
+pub(super) fn fir(history: &[i16; 8], coefficients: &[i16; 8]) -> i16 {
+    let tap = |idx: usize| (history[idx] as i32 * coefficients[idx] as i32) >> 6;
+
+    let sum = (0..7).map(tap).sum::<i32>() as i16;
+    let sum = clamp16(sum as i32 + tap(7) as i16 as i32);
+
+    sum & !1
+}
+
+fn output(main: i16, echo_in: i16, master_volume: u8, echo_volume: u8) -> i16 {
+    clamp16(apply_volume(main, master_volume) as i32 + apply_volume(echo_in, echo_volume) as i32)
+}
+
+fn echo_feedback(echo: i16, echo_in: i16, feedback_volume: u8) -> i16 {
+    clamp16(echo as i32 + apply_volume(echo_in, feedback_volume) as i32) & !1
+}
+
+// Mute by FLG only silences DAC, and echo keeps running behind it.
+// DAC output is inverted.
+fn to_dac(sample: i16, is_mute: bool) -> i16 {
+    if is_mute { 0 } else { sample.saturating_neg() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulation_clamps_at_each_voice() {
+        let samples = [(0x7000, -0x7000), (0x2000, -0x2000), (-0x1000, 0x1000)];
+
+        // without clamping at each voice, these would be 0x7FFF and -0x8000
+        assert_eq!(accumulate(samples.into_iter()), (0x6FFF, -0x7000));
+    }
+
+    #[test]
+    fn volume_product_wraps() {
+        assert_eq!(apply_volume(0x4000, 0x7F), 0x3F80);
+        assert_eq!(apply_volume(0x4000, 0x80), -0x4000);
+        // -0x8000 * -128 >> 7 = 0x8000, which does not fit into 16 bits
+        assert_eq!(apply_volume(-0x8000, 0x80), -0x8000);
+    }
+
+    #[test]
+    fn fir_wraps_first_taps_and_clamps_last_tap() {
+        let coefficients = [0x7F, 0x7F, 0, 0, 0, 0, 0, 0x7F];
+        let history = [0x3FFF, 0x3FFF, 0, 0, 0, 0, 0, 0x3FFF];
+        // (0x3FFF * 0x7F) >> 6 = 0x7EFE for each tap
+        // first 2 taps wrap: 0xFDFC as i16 = -0x0204, and then 0x7CFA is added without overflow
+        assert_eq!(fir(&history, &coefficients), 0x7CFA);
+
+        let history = [0, 0, 0, 0, 0, 0, 0x3FFF, 0x3FFF];
+        let coefficients = [0, 0, 0, 0, 0, 0, 0x7F, 0x7F];
+        assert_eq!(fir(&history, &coefficients), 0x7FFE);
+    }
+
+    #[test]
+    fn fir_clears_lsb() {
+        let history = [0, 0, 0, 0, 0, 0, 0, 0x0041];
+        let coefficients = [0, 0, 0, 0, 0, 0, 0, 0x40];
+        assert_eq!(fir(&history, &coefficients), 0x0040);
+    }
+
+    #[test]
+    fn output_saturates() {
+        assert_eq!(output(0x7FFF, 0x7FFF, 0x7F, 0x7F), 0x7FFF);
+        assert_eq!(output(-0x8000, -0x8000, 0x7F, 0x7F), -0x8000);
+        assert_eq!(output(0x1000, -0x0800, 0x40, 0x7F), 0x0010);
+    }
+
+    #[test]
+    fn echo_feedback_saturates_and_clears_lsb() {
+        assert_eq!(echo_feedback(0x7000, 0x7000, 0x7F), 0x7FFE);
+        assert_eq!(echo_feedback(0x0101, 0, 0), 0x0100);
+        assert_eq!(echo_feedback(-0x0101, 0x0200, 0x40), -0x0002);
+    }
+
+    #[test]
+    fn dac_is_inverted_and_muted() {
+        assert_eq!(to_dac(0x1234, false), -0x1234);
+        assert_eq!(to_dac(-0x8000, false), 0x7FFF);
+        assert_eq!(to_dac(0x1234, true), 0);
+    }
+}
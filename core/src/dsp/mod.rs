@@ -2,6 +2,10 @@ mod gaussian_table;
 mod envelope;
 mod block;
 mod brr;
+mod mixer;
+mod state;
+mod sample;
+mod audition;
 
 use std::u8;
 use std::i16;
@@ -9,10 +13,17 @@ use std::u16;
 
 use array_macro::array;
 
-use crate::processor::ram::Ram;
 use block::DSPBlock;
 use brr::FilterType;
 
+use crate::event::{self, DspEventKind};
+
+pub use envelope::ADSRMode;
+pub(crate) use envelope::rate_period;
+pub use state::{DspState, VoiceState};
+pub use sample::BrrSample;
+pub use audition::Audition;
+
 const SAMPLE_BUFFER_SIZE: usize = 16 + 3;
 pub const CYCLE_RANGE: u16 = 30720;
 
@@ -45,6 +56,13 @@ pub struct DSP {
 
     sample_left_out: i16,
     sample_right_out: i16,
+    output_enable: bool,
+    pitch_shift: PitchShift,
+    // KON bits written since the last `take_key_on`
+    key_on_flags: u8,
+    // events are recorded only while enabled, and taken by `drain_events`
+    record_events: bool,
+    events: Vec<DspEventKind>,
 
     // global dsp counter
     counter: u16,    
@@ -125,6 +143,36 @@ impl DSPRegister {
     }
 }
 
+// Host side pitch shift of all voices.
+#[derive(Clone, Copy)]
+pub struct PitchShift {
+    // ratio of step in 16.16 fixed point
+    ratio: u32,
+    // step may exceed 14bit hardware limit up to 16bit pitch counter
+    unlimited: bool,
+}
+
+impl PitchShift {
+    pub const fn new() -> PitchShift {
+        PitchShift { ratio: 0x10000, unlimited: false }
+    }
+
+    pub fn with_semitones(semitones: f32, unlimited: bool) -> PitchShift {
+        let ratio = (2f32.powf(semitones / 12.0) * 65536.0).round();
+        PitchShift { ratio: ratio.clamp(0.0, u32::MAX as f32) as u32, unlimited }
+    }
+
+    pub fn apply(&self, step: u16) -> u16 {
+        if self.ratio == 0x10000 {
+            return step;
+        }
+
+        let limit = if self.unlimited { 0xFFFF } else { 0x3FFF };
+        let step = (step as u64 * self.ratio as u64) >> 16;
+        step.min(limit) as u16
+    }
+}
+
 struct FIR {
     regs: [i16; 8],    
     filter: [i16; 8],
@@ -145,20 +193,12 @@ impl FIR {
         }
     }
 
+    // `value` is a sample read from echo buffer. History keeps it halved.
     pub fn next(&mut self, value: i16) -> i16 {
-        let mut new_regs = [0; 8];
-        self.regs[1..].iter().zip(0..).for_each(|(&v, idx)| new_regs[idx] = v);
-        new_regs[7] = value;
-
-        let ret = new_regs.iter().zip(self.filter.iter())
-            .map(|(&value, &filter)| ((value as i32) * (filter as i32)) >> 7)
-            .fold(0, |acc, value| { acc + value });
+        self.regs.copy_within(1.., 0);
+        self.regs[7] = value >> 1;
 
-        self.regs = new_regs;        
-        
-        if ret > 0x7FFF       {  0x7FFF }
-        else if ret < -0x8000 { -0x8000 }
-        else                  { ret as i16 }
+        mixer::fir(&self.regs, &self.filter)
     }
 }
 
@@ -200,6 +240,11 @@ impl DSP {
 
             sample_left_out: 0,
             sample_right_out: 0,
+            output_enable: true,
+            pitch_shift: PitchShift::new(),
+            key_on_flags: 0,
+            record_events: false,
+            events: Vec::new(),
 
             counter: 0,
             sync_counter: 0,
@@ -234,6 +279,8 @@ impl DSP {
         dsp.echo_vol_left = regs[0x2C];
         dsp.echo_vol_right = regs[0x3C];
         dsp.table_addr = regs[0x5D];
+        dsp.key_on_flags = 0;
+        dsp.events.clear();
 
         dsp.flag_is_modified = true;        
 
@@ -247,6 +294,10 @@ impl DSP {
         dsp.echo_ring_buffer_addr = (regs[0x6D] as u16) << 8;
         dsp.echo_buffer_size = regs[0x7D];
         dsp.echo_buf_length = calc_echo_buffer_size(regs[0x7D]);
+        // state which is not in the snapshot starts over, so playback from a snapshot is always the same
+        dsp.echo_pos = 0;
+        dsp.counter = 0;
+        dsp.sync_counter = 0;
         
         let mut fir_coefficients = [0; 8];
         (0..8).map(|upper: usize| regs[(upper << 4) | 0x0F])
@@ -265,35 +316,92 @@ impl DSP {
         self.sync_counter += cycle_count
     }
 
-    pub fn flush(&mut self) -> () {       
+    // Returns true if a new sample is generated.
+    pub fn flush(&mut self) -> bool {       
         let flush_count = self.sync_counter / 64;
         if flush_count != 0 {
             let next_sync_counter = self.sync_counter % 64;
             self.exec_flush();
             self.sync_counter = next_sync_counter;
         } 
+
+        flush_count != 0
     }
 
     fn exec_flush(&mut self) -> () {        
         let soft_reset = self.soft_reset && self.flag_is_modified;
         let cycle_counter = self.counter;            
 
-        self.blocks.iter_mut().fold(Option::<i16>::None, |before_out, blk| {                                    
-            blk.flush(before_out, soft_reset, cycle_counter);
+        // voice output is still required while output is disabled
+        // if the next voice uses it for pitch modulation, or it is written into echo buffer.
+        let mut require_out = [self.output_enable; 8];
+        require_out[..7].iter_mut().zip(self.blocks[1..].iter()).for_each(|(require, next)| {
+            *require |= next.reg.pmon_enable;
+        });
+        require_out.iter_mut().zip(self.blocks.iter()).for_each(|(require, blk)| {
+            *require |= self.echo_buffer_enable && blk.reg.echo_enable;
+        });
+
+        let voice_end = vec_to_u8(self.blocks.iter().map(|blk| blk.reg.voice_end));
+        let pitch_shift = self.pitch_shift;
+        self.blocks.iter_mut().zip(require_out).fold(Option::<i16>::None, |before_out, (blk, require_out)| {                                    
+            blk.flush(before_out, soft_reset, cycle_counter, require_out, &pitch_shift);
             Some(blk.sample_out)
         });
 
-        let (left, right) = combine_all_sample(&self.blocks);         
-        let (echo_left, echo_right) = combine_echo(&self.blocks);        
-        let (left_echo, right_echo) = echo_process(echo_left, echo_right, self);
+        if self.record_events {
+            let ended = vec_to_u8(self.blocks.iter().map(|blk| blk.reg.voice_end)) & !voice_end;
+            self.events.extend(event::voices(ended).map(|voice| DspEventKind::VoiceEnd { voice }));
+        }
 
-        let left_out = (left as i32) + (left_echo as i32);
-        let right_out = (right as i32) + (right_echo as i32);
+        let (left_out, right_out) = 
+            if self.output_enable {
+                mixer::mix(self)
+            } else {
+                mixer::skip(self);
+                (0, 0)
+            };
         
         self.flag_is_modified = false;
         self.counter = (self.counter + 1) % CYCLE_RANGE;
-        self.sample_left_out = left_out as i16;
-        self.sample_right_out = right_out as i16; 
+        self.sample_left_out = left_out;
+        self.sample_right_out = right_out; 
+    }
+
+    // While output is disabled, DSP skips interpolation of voices which are not echoed,
+    // and main mixing, but still updates envelopes, BRR positions and echo buffer in RAM.
+    // This is used to seek quickly.
+    pub fn set_output_enable(&mut self, enable: bool) {
+        self.output_enable = enable;
+    }
+
+    pub fn set_pitch_shift(&mut self, pitch_shift: PitchShift) {
+        self.pitch_shift = pitch_shift;
+    }
+
+    pub(crate) fn set_record_events(&mut self, enable: bool) {
+        self.record_events = enable;
+        if !enable {
+            self.events.clear();
+        }
+    }
+
+    pub(crate) fn drain_events(&mut self) -> std::vec::Drain<'_, DspEventKind> {
+        self.events.drain(..)
+    }
+
+    // Returns voices keyed on since the last call.
+    pub(crate) fn take_key_on(&mut self) -> u8 {
+        std::mem::take(&mut self.key_on_flags)
+    }
+
+    pub(crate) fn is_all_voices_released(&self) -> bool {
+        (0..8).all(|voice| self.is_voice_released(voice))
+    }
+
+    pub(crate) fn is_voice_released(&self, voice: usize) -> bool {
+        let blk = &self.blocks[voice];
+        blk.envelope.level == 0 && blk.key_on_delay == 0
     }
 
     pub fn read_from_register(&mut self, addr: usize) -> u8 {
@@ -332,12 +440,18 @@ impl DSP {
             (  0x7, 0xD) => self.echo_buffer_size,
             (upper, 0xE) => self.unused_e[upper],
             (upper, 0xF) => self.fir_left.filter[upper] as u8,         
-            _ => panic!("{:#06x} is not unexpected address", addr),
+            _ => {
+                log::warn!("{:#06x} is not expected address", addr);
+                0
+            }
         }                
     }
 
     pub fn write_to_register(&mut self, addr: usize, data: u8) -> () {                
         // self.flush(ram);
+        if self.record_events {
+            self.record_write(addr, data);
+        }
 
         let upper = (addr >> 4) & 0x0F;
         let lower = addr & 0x0F;
@@ -382,6 +496,7 @@ impl DSP {
             (  0x2, 0xC) => self.echo_vol_left = data,
             (  0x3, 0xC) => self.echo_vol_right = data,
             (  0x4, 0xC) => {                
+                self.key_on_flags |= data;
                 let bools = u8_to_vec(data);                
                 self.blocks.iter_mut()
                     .zip(bools)
@@ -440,7 +555,32 @@ impl DSP {
                 self.fir_left.filter[upper] = (data as i8) as i16;
                 self.fir_right.filter[upper] = (data as i8) as i16; 
             }
-            _ => panic!("{:#06x} is not expected address", addr),
+            _ => log::warn!("{:#06x} is not expected address", addr),
+        }
+    }
+
+    // Records the write and events derived from it. Register values are not updated yet.
+    fn record_write(&mut self, addr: usize, data: u8) {
+        self.events.push(DspEventKind::RegisterWrite { addr: addr as u8, data });
+
+        let voice = (addr >> 4) & 0x0F;
+        match (voice, addr & 0x0F) {
+            (0x0..=0x7, lower @ (0x2 | 0x3)) => {
+                let pitch = self.blocks[voice].reg.pitch;
+                let new_pitch =
+                    if lower == 0x2 { (pitch & 0xFF00) | data as u16 }
+                    else { (pitch & 0x00FF) | ((data as u16 & 0x3F) << 8) };
+
+                if new_pitch != pitch {
+                    self.events.push(DspEventKind::PitchChange { voice, pitch: new_pitch });
+                }
+            }
+            (0x0..=0x7, 0x4) if self.blocks[voice].reg.srcn != data => {
+                self.events.push(DspEventKind::SourceChange { voice, srcn: data });
+            }
+            (0x4, 0xC) => self.events.extend(event::voices(data).map(|voice| DspEventKind::KeyOn { voice })),
+            (0x5, 0xC) => self.events.extend(event::voices(data).map(|voice| DspEventKind::KeyOff { voice })),
+            _ => (),
         }
     }
 
@@ -471,76 +611,20 @@ impl DSP {
     pub fn sample_right_out(&self) -> i16 { self.sample_right_out }    
 }
 
-// TODO: need echo accumulate implementation
-fn combine_all_sample(blocks: &[DSPBlock]) -> (i16, i16) {
-    let dsp = DSP::global();
-
-    if dsp.is_mute {
-        (0, 0)
-    } else {
-        let left = blocks.iter().map(|blk| blk.sample_left as i32).sum::<i32>() * dsp.master_vol_left as i32 >> 7;
-        let right = blocks.iter().map(|blk| blk.sample_right as i32).sum::<i32>() * dsp.master_vol_right as i32 >> 7;
-
-        let left = left.min(0x7FFF).max(-0x8000) as i16;
-        let right = right.min(0x7FFF).max(-0x8000) as i16;
-
-        (left, right)
-    } 
-}
-
-fn combine_echo(blocks: &[DSPBlock]) -> (i16, i16) {
-    let left = blocks.iter().map(|blk| blk.echo_left as i32).sum::<i32>();
-    let right = blocks.iter().map(|blk| blk.echo_right as i32).sum::<i32>();
-
-    let left = left.min(0x7FFF).max(-0x8000) as i16;
-    let right = right.min(0x7FFF).max(-0x8000) as i16;
-
-    (left, right)
-}
-
-fn echo_process(left: i16, right: i16, dsp: &mut DSP) -> (i16, i16) {    
-    let buffer_addr = ((dsp.echo_ring_buffer_addr + dsp.echo_pos) & 0xFFFF) as usize;
-
-    let (left_out, left_new_echo) = echo_process_inner(left, buffer_addr, dsp.echo_feedback_volume as i8, dsp.echo_vol_left as i8, &mut dsp.fir_left);
-    let (right_out, right_new_echo) = echo_process_inner(right, buffer_addr + 2, dsp.echo_feedback_volume as i8, dsp.echo_vol_right as i8, &mut dsp.fir_right);    
-
-    if dsp.echo_buffer_enable {
-        let left_lower  = left_new_echo as u8;
-        let left_upper  = (left_new_echo >> 8) as u8;
-        let right_lower = right_new_echo as u8;
-        let right_upper = (right_new_echo >> 8) as u8;
-
-        let ram = &mut Ram::global().ram[buffer_addr..];
-        ram[0] = left_lower;
-        ram[1] = left_upper;
-        ram[2] = right_lower;
-        ram[3] = right_upper; 
+// Fills `buffer` with interleaved stereo samples from `next_sample`, pads an odd sample with 0,
+// and returns the number of rendered frames.
+pub(crate) fn render_frames(buffer: &mut [i16], mut next_sample: impl FnMut() -> (i16, i16)) -> usize {
+    let mut frames = buffer.chunks_exact_mut(2);
+    let mut count = 0;
+    for frame in &mut frames {
+        let (left, right) = next_sample();
+        frame[0] = left;
+        frame[1] = right;
+        count += 1;
     }
- 
-    dsp.echo_pos += 4;
-    if dsp.echo_pos >= dsp.echo_buf_length {
-        dsp.echo_pos = 0;
-    }
-
-    (left_out as i16, right_out as i16)
-}
-
-fn echo_process_inner(echo_sample: i16, addr: usize, feedback_volume: i8, out_volume: i8, fir: &mut FIR) -> (i32, i16) {
-    let sample0 = (Ram::global().ram[addr + 1] as u16) << 8;
-    let sample1 = Ram::global().ram[addr] as u16;
-    let buf_echo = sample0 | sample1; 
-    let fir_out = fir.next(buf_echo as i16); 
-
-    let out_echo = ((fir_out as i32) * (out_volume as i32)) >> 7;
-    let new_echo = (echo_sample as i32) + (((fir_out as i32) * (feedback_volume as i32)) >> 7);
-    let new_echo = 
-        if new_echo > 0x7FFF { 0x7FFF }
-        else if new_echo < -0x8000 { -0x8000 }
-        else { new_echo };
-
-    let new_echo = (new_echo as u16) & 0xFFFE;
+    frames.into_remainder().fill(0);
 
-    (out_echo, new_echo as i16)
+    count
 }
 
 fn u8_to_vec(v: u8) -> impl Iterator<Item = bool> {
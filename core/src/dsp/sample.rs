@@ -0,0 +1,74 @@
+use super::SAMPLE_BUFFER_SIZE;
+use super::block::{fetch_brr_block, generate_new_sample};
+use super::brr::{BRRInfo, BRREnd};
+
+use crate::processor::ram::Ram;
+
+const BRR_BLOCK_SIZE: u16 = 9;
+const SAMPLES_PER_BLOCK: usize = 16;
+// guard against samples without end flag, which would run through the whole RAM
+const MAX_BLOCKS: usize = 0x10000 / BRR_BLOCK_SIZE as usize;
+
+// BRR sample of the sample directory decoded into 16bit PCM at 32kHz.
+// Pitch 0x1000 plays it at the original rate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrrSample {
+    pub srcn: u8,
+    pub start_addr: u16,
+    pub loop_addr: u16,
+    pub data: Vec<i16>,
+    // index of the first sample of the loop in `data`, None if the voice stops at the end
+    pub loop_start: Option<usize>,
+}
+
+impl BrrSample {
+    // Decodes `srcn` of the sample directory at `table_addr` in the loaded RAM.
+    // If the loop does not start at a block of the sample itself, the loop is decoded after the sample.
+    pub fn decode(table_addr: u16, srcn: u8) -> BrrSample {
+        let ram = Ram::global();
+        let entry = table_addr.wrapping_add(srcn as u16 * 4);
+        let read_u16 = |addr: u16| ram.read_ram(addr) as u16 | ((ram.read_ram(addr.wrapping_add(1)) as u16) << 8);
+        let start_addr = read_u16(entry);
+        let loop_addr = read_u16(entry.wrapping_add(2));
+
+        let mut buffer = [0; SAMPLE_BUFFER_SIZE];
+        let mut data = Vec::new();
+        let mut blocks = Vec::new();
+        let is_loop = decode_blocks(start_addr, &mut buffer, &mut data, &mut blocks);
+
+        let loop_start =
+            if !is_loop {
+                None
+            } else if let Some(idx) = blocks.iter().position(|&addr| addr == loop_addr) {
+                Some(idx * SAMPLES_PER_BLOCK)
+            } else {
+                let loop_start = data.len();
+                decode_blocks(loop_addr, &mut buffer, &mut data, &mut blocks);
+                Some(loop_start)
+            };
+
+        BrrSample { srcn, start_addr, loop_addr, data, loop_start }
+    }
+}
+
+// Decodes blocks from `addr` until end flag, and returns whether the last block has loop flag.
+fn decode_blocks(addr: u16, buffer: &mut [i16; SAMPLE_BUFFER_SIZE], data: &mut Vec<i16>, blocks: &mut Vec<u16>) -> bool {
+    let mut addr = addr;
+    while blocks.len() < MAX_BLOCKS {
+        let block = fetch_brr_block(addr);
+        let info = BRRInfo::new(block[0]);
+        generate_new_sample(&block[1..], buffer, &info);
+
+        // buffer holds 15bit samples
+        data.extend(buffer[3..].iter().map(|&sample| sample << 1));
+        blocks.push(addr);
+
+        match info.end {
+            BRREnd::Normal => addr = addr.wrapping_add(BRR_BLOCK_SIZE),
+            BRREnd::Loop => return true,
+            BRREnd::Mute => return false,
+        }
+    }
+
+    false
+}
@@ -0,0 +1,109 @@
+use super::DSP;
+use super::block::DSPBlock;
+use super::envelope::ADSRMode;
+
+// Read-only snapshot of a voice.
+// Volumes are signed values as the DSP interprets them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoiceState {
+    // keyed on and not released yet, including the delay just after KON
+    pub key_on: bool,
+    // KOFF bit
+    pub key_off: bool,
+    pub adsr_mode: ADSRMode,
+    // 11bit envelope level (0..=0x7FF)
+    pub envelope_level: i16,
+    // 14bit pitch register (0x1000 is the original pitch)
+    pub pitch: u16,
+    pub srcn: u8,
+    pub adsr: u16,
+    pub gain: u8,
+    pub volume_left: i8,
+    pub volume_right: i8,
+    // address of BRR block currently decoded
+    pub brr_addr: u16,
+    pub loop_addr: u16,
+    // current BRR block has loop flag
+    pub is_loop: bool,
+    // ENDX bit
+    pub voice_end: bool,
+    pub echo_enable: bool,
+    pub noise_enable: bool,
+    pub pmon_enable: bool,
+    // voice output after volume, which is added to main output
+    pub out_left: i16,
+    pub out_right: i16,
+}
+
+// Read-only snapshot of the whole DSP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DspState {
+    pub voices: [VoiceState; 8],
+    pub master_volume_left: i8,
+    pub master_volume_right: i8,
+    pub echo_volume_left: i8,
+    pub echo_volume_right: i8,
+    pub echo_feedback: i8,
+    // FIR coefficients from C0 to C7
+    pub fir_coefficients: [i8; 8],
+    // echo buffer region is `echo_buffer_addr..echo_buffer_addr + echo_buffer_len` and wraps around in RAM
+    pub echo_buffer_addr: u16,
+    pub echo_buffer_len: u16,
+    // echo writes to RAM are enabled by FLG
+    pub echo_write_enable: bool,
+    pub is_mute: bool,
+    // address of sample directory (DIR * 0x100)
+    pub sample_table_addr: u16,
+    pub out_left: i16,
+    pub out_right: i16,
+}
+
+impl VoiceState {
+    fn new(blk: &DSPBlock) -> VoiceState {
+        VoiceState {
+            key_on: blk.key_on_delay > 0 || blk.envelope.adsr_mode != ADSRMode::Release,
+            key_off: blk.reg.key_off,
+            adsr_mode: blk.envelope.adsr_mode,
+            envelope_level: blk.envelope.level,
+            pitch: blk.reg.pitch,
+            srcn: blk.reg.srcn,
+            adsr: blk.reg.adsr,
+            gain: blk.reg.gain,
+            volume_left: blk.reg.vol_left as i8,
+            volume_right: blk.reg.vol_right as i8,
+            brr_addr: blk.src_addr,
+            loop_addr: blk.loop_addr,
+            is_loop: blk.is_loop,
+            voice_end: blk.reg.voice_end,
+            echo_enable: blk.reg.echo_enable,
+            noise_enable: blk.reg.noise_enable,
+            pmon_enable: blk.reg.pmon_enable,
+            out_left: blk.sample_left,
+            out_right: blk.sample_right,
+        }
+    }
+}
+
+impl DSP {
+    pub fn state(&self) -> DspState {
+        let mut fir_coefficients = [0; 8];
+        fir_coefficients.iter_mut().zip(self.fir_left.filter.iter()).for_each(|(dst, &src)| *dst = src as i8);
+
+        DspState {
+            voices: [0, 1, 2, 3, 4, 5, 6, 7].map(|idx| VoiceState::new(&self.blocks[idx])),
+            master_volume_left: self.master_vol_left as i8,
+            master_volume_right: self.master_vol_right as i8,
+            echo_volume_left: self.echo_vol_left as i8,
+            echo_volume_right: self.echo_vol_right as i8,
+            echo_feedback: self.echo_feedback_volume as i8,
+            fir_coefficients,
+            echo_buffer_addr: self.echo_ring_buffer_addr,
+            echo_buffer_len: self.echo_buf_length,
+            echo_write_enable: self.echo_buffer_enable,
+            is_mute: self.is_mute,
+            sample_table_addr: (self.table_addr as u16) << 8,
+            out_left: self.sample_left_out,
+            out_right: self.sample_right_out,
+        }
+    }
+}
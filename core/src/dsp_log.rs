@@ -0,0 +1,304 @@
+use crate::dsp::{render_frames, DSP, PitchShift};
+use crate::error::{Result, Spc700Error};
+use crate::processor::ram::Ram;
+
+const MAGIC: &[u8; 8] = b"SPCDSPLG";
+const VERSION: u8 = 1;
+const RAM_SIZE: usize = 0x10000;
+const EXTRA_RAM_SIZE: usize = 64;
+const REGS_SIZE: usize = 128;
+
+// Write into a DSP register through $F2/$F3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DspWrite {
+    // CPU cycles since the beginning of the song, at the beginning of the instruction
+    pub cycle: u64,
+    // index of the first sample generated after the write
+    pub sample: u64,
+    pub addr: u8,
+    pub data: u8,
+}
+
+// Initial RAM and DSP registers of a song, and all DSP writes made by its program.
+// This is enough to play the song without CPU, as long as the program does not rewrite samples in RAM.
+// Writes must be sorted by `sample`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DspLog {
+    pub ram: Box<[u8; 0x10000]>,
+    // IPL region shown at 0xFFC0 while the boot ROM is disabled
+    pub extra_ram: [u8; 64],
+    pub regs: [u8; 128],
+    pub writes: Vec<DspWrite>,
+    // number of recorded samples
+    pub samples: u64,
+}
+
+impl DspLog {
+    // File layout (little endian):
+    //   magic "SPCDSPLG", version, number of samples (u64),
+    //   RAM, extra RAM, DSP registers, number of writes (u32),
+    //   and each write as sample delta and cycle delta in LEB128, address and data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RAM_SIZE + self.writes.len() * 4 + 256);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.samples.to_le_bytes());
+        bytes.extend_from_slice(&self.ram[..]);
+        bytes.extend_from_slice(&self.extra_ram);
+        bytes.extend_from_slice(&self.regs);
+        bytes.extend_from_slice(&(self.writes.len() as u32).to_le_bytes());
+
+        let mut last = (0, 0);
+        for write in self.writes.iter() {
+            write_leb128(&mut bytes, write.sample.wrapping_sub(last.0));
+            write_leb128(&mut bytes, write.cycle.wrapping_sub(last.1));
+            bytes.push(write.addr);
+            bytes.push(write.data);
+            last = (write.sample, write.cycle);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DspLog> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(Spc700Error::BadHeader("DSP log signature is not found".to_string()));
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(Spc700Error::UnsupportedVersion(format!("DSP log version {}", version)));
+        }
+
+        let samples = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        let mut ram = Box::new([0; RAM_SIZE]);
+        ram.copy_from_slice(reader.take(RAM_SIZE)?);
+        let extra_ram = reader.take(EXTRA_RAM_SIZE)?.try_into().unwrap();
+        let regs = reader.take(REGS_SIZE)?.try_into().unwrap();
+
+        let count = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        let mut writes = Vec::with_capacity(count.min(0x100000) as usize);
+        let mut last = (0u64, 0u64);
+        for _ in 0..count {
+            let sample = last.0.wrapping_add(reader.leb128()?);
+            let cycle = last.1.wrapping_add(reader.leb128()?);
+            let data = reader.take(2)?;
+            if sample < last.0 {
+                return Err(Spc700Error::InvalidData("DSP writes are not sorted by sample".to_string()));
+            }
+
+            writes.push(DspWrite { cycle, sample, addr: data[0], data: data[1] });
+            last = (sample, cycle);
+        }
+
+        Ok(DspLog { ram, extra_ram, regs, writes, samples })
+    }
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, value: u64) {
+    let mut value = value;
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self.bytes.get(self.pos..self.pos + len)
+            .ok_or(Spc700Error::TruncatedFile { expected: self.pos + len, actual: self.bytes.len() })?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn leb128(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if (byte & 0x80) == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Spc700Error::InvalidData("too long LEB128 in DSP log".to_string()))
+    }
+}
+
+// Plays DSP log by writing DSP registers at recorded samples. CPU and timers do not run.
+// Output is raw DSP output, which is the same as the song played from the beginning
+// without pitch shift, output model and post mix.
+pub struct DspReplay {
+    writes: Vec<DspWrite>,
+    next: usize,
+    samples: u64,
+    length: u64,
+}
+
+impl DspReplay {
+    // Replaces RAM and DSP of the loaded song by the log.
+    pub fn new(log: &DspLog) -> DspReplay {
+        Ram::init(&log.ram, &log.extra_ram);
+        DSP::init(&log.regs);
+
+        let dsp = DSP::global();
+        dsp.set_pitch_shift(PitchShift::new());
+        dsp.set_record_events(false);
+        dsp.set_output_enable(true);
+
+        DspReplay { writes: log.writes.clone(), next: 0, samples: 0, length: log.samples }
+    }
+
+    // Number of generated samples.
+    pub fn position(&self) -> u64 {
+        self.samples
+    }
+
+    // Whether all recorded samples are generated. Samples after that are generated without writes.
+    pub fn is_finished(&self) -> bool {
+        self.samples >= self.length
+    }
+
+    pub fn next_sample(&mut self) -> (i16, i16) {
+        let dsp = DSP::global();
+        while let Some(write) = self.writes.get(self.next).filter(|write| write.sample <= self.samples) {
+            dsp.write_to_register(write.addr as usize, write.data);
+            self.next += 1;
+        }
+
+        dsp.cycles(64);
+        dsp.flush();
+        self.samples += 1;
+
+        (dsp.sample_left_out(), dsp.sample_right_out())
+    }
+
+    // Fills `buffer` with interleaved stereo samples and returns the number of rendered frames.
+    pub fn render(&mut self, buffer: &mut [i16]) -> usize {
+        render_frames(buffer, || self.next_sample())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::processor::Spc700;
+    use crate::testing::{lock_globals, spc_file};
+
+    // header before writes: magic, version, samples, RAM, extra RAM, registers and count
+    const WRITES_POS: usize = 8 + 1 + 8 + RAM_SIZE + EXTRA_RAM_SIZE + REGS_SIZE + 4;
+
+    fn log(writes: &[(u64, u64)]) -> DspLog {
+        let mut ram = Box::new([0; RAM_SIZE]);
+        ram.iter_mut().enumerate().for_each(|(idx, byte)| *byte = (idx * 7) as u8);
+        let writes = writes.iter().enumerate()
+            .map(|(idx, &(sample, cycle))| DspWrite { cycle, sample, addr: idx as u8, data: !(idx as u8) })
+            .collect();
+        DspLog { ram, extra_ram: [0xC0; 64], regs: [0x5D; 128], writes, samples: 1 << 40 }
+    }
+
+    fn leb128(value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_leb128(&mut bytes, value);
+        bytes
+    }
+
+    #[test]
+    fn leb128_roundtrip() {
+        assert_eq!(leb128(0), [0x00]);
+        assert_eq!(leb128(0x7F), [0x7F]);
+        assert_eq!(leb128(0x80), [0x80, 0x01]);
+        assert_eq!(leb128(300), [0xAC, 0x02]);
+        assert_eq!(leb128(u64::MAX).len(), 10);
+
+        for value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 35, u64::MAX] {
+            let bytes = leb128(value);
+            let mut reader = Reader { bytes: &bytes, pos: 0 };
+            assert_eq!(reader.leb128().unwrap(), value);
+            assert_eq!(reader.pos, bytes.len());
+        }
+
+        let mut reader = Reader { bytes: &[0x80; 11], pos: 0 };
+        assert!(matches!(reader.leb128(), Err(Spc700Error::InvalidData(_))));
+        let mut reader = Reader { bytes: &[0x80, 0x80], pos: 0 };
+        assert!(matches!(reader.leb128(), Err(Spc700Error::TruncatedFile { .. })));
+    }
+
+    #[test]
+    fn roundtrip_keeps_log() {
+        // writes in the same sample, and deltas of several LEB128 bytes
+        let original = log(&[(0, 0), (0, 12), (1, 40), (200, 6400), (1 << 33, 1 << 38)]);
+        let bytes = original.to_bytes();
+        assert_eq!(&bytes[..9], b"SPCDSPLG\x01");
+        assert_eq!(&bytes[WRITES_POS - 4..WRITES_POS], &5u32.to_le_bytes());
+        // sample and cycle deltas of the third write
+        assert_eq!(&bytes[WRITES_POS + 8..WRITES_POS + 12], &[0x01, 0x1C, 0x02, 0xFD]);
+
+        assert_eq!(DspLog::from_bytes(&bytes).unwrap(), original);
+        assert_eq!(DspLog::from_bytes(&log(&[]).to_bytes()).unwrap(), log(&[]));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let mut bytes = log(&[(0, 0)]).to_bytes();
+        bytes[0] = b'X';
+        assert!(matches!(DspLog::from_bytes(&bytes), Err(Spc700Error::BadHeader(_))));
+
+        let mut bytes = log(&[(0, 0)]).to_bytes();
+        bytes[8] = VERSION + 1;
+        assert!(matches!(DspLog::from_bytes(&bytes), Err(Spc700Error::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn rejects_unsorted_writes() {
+        // delta of the second write wraps around to go back
+        let bytes = log(&[(10, 320), (9, 330)]).to_bytes();
+        assert!(matches!(DspLog::from_bytes(&bytes), Err(Spc700Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let bytes = log(&[(0, 0), (1, 40), (1 << 33, 1 << 38)]).to_bytes();
+        let cuts = [0, 5, 8, 12, 17, 0x1000, WRITES_POS - 5, WRITES_POS - 1, WRITES_POS + 1, WRITES_POS + 3];
+        for len in cuts.into_iter().chain(WRITES_POS + 4..bytes.len()) {
+            let result = DspLog::from_bytes(&bytes[..len]);
+            assert!(matches!(result, Err(Spc700Error::TruncatedFile { .. })), "{}", len);
+        }
+    }
+
+    #[test]
+    fn replay_equals_emulation() {
+        let _lock = lock_globals();
+        let write = |addr: u8, data: u8| [0x8F, addr, 0xF2, 0x8F, data, 0xF3];
+        // waits 20 * 256 iterations of 6 cycles
+        let wait = [0x8D, 20, 0xCD, 0x00, 0x1D, 0xD0, 0xFD, 0xDC, 0xD0, 0xF8];
+        let program = [
+            &write(0x00, 0x50)[..], &write(0x01, 0x30), &write(0x02, 0x00), &write(0x03, 0x10), &write(0x4C, 0x01),
+            &wait, &write(0x03, 0x08), &write(0x00, 0x20),
+            &wait, &write(0x4C, 0x01), &write(0x01, 0x7F),
+            &[0x2F, 0xFE],
+        ].concat();
+        let regs = [(0x05, 0x8F), (0x06, 0xE0), (0x0C, 0x7F), (0x1C, 0x7F), (0x6C, 0x20)];
+
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(&program, &regs)).unwrap();
+        let log = emulator.record_dsp_log(Duration::from_millis(100)).unwrap();
+        assert_eq!(log.writes.iter().filter(|write| write.addr == 0x4C).count(), 2);
+
+        let mut expected = vec![0; log.samples as usize * 2];
+        emulator.render(&mut expected);
+        assert!(expected.iter().any(|&sample| sample != 0));
+
+        let mut replayed = vec![0; expected.len()];
+        DspReplay::new(&log).render(&mut replayed);
+        assert_eq!(replayed, expected);
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+
+#[derive(Debug)]
+pub enum Spc700Error {
+    BadHeader(String),
+    TruncatedFile { expected: usize, actual: usize },
+    UnsupportedVersion(String),
+    UnsupportedArchive(String),
+    InvalidState(String),
+    InvalidData(String),
+    EmulationFault { pc: u16, opcode: u8 },
+    Io(io::Error),
+}
+
+impl Display for Spc700Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Spc700Error::BadHeader(msg) => write!(f, "bad header: {}", msg),
+            Spc700Error::TruncatedFile { expected, actual } => write!(f, "truncated file: expected {} bytes, but got {} bytes", expected, actual),
+            Spc700Error::UnsupportedVersion(version) => write!(f, "unsupported version: {}", version),
+            Spc700Error::UnsupportedArchive(msg) => write!(f, "unsupported archive: {}", msg),
+            Spc700Error::InvalidState(msg) => write!(f, "invalid state: {}", msg),
+            Spc700Error::InvalidData(msg) => write!(f, "invalid data: {}", msg),
+            Spc700Error::EmulationFault { pc, opcode } => write!(f, "emulation fault: opcode {:#04x} at pc {:#06x}", opcode, pc),
+            Spc700Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Spc700Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Spc700Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Spc700Error {
+    fn from(err: io::Error) -> Spc700Error {
+        Spc700Error::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Spc700Error>;
@@ -0,0 +1,31 @@
+// Events emitted by DSP while the song is played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DspEvent {
+    // CPU cycles since the snapshot is loaded, at the beginning of the instruction causing this event
+    pub cycle: u64,
+    // index of the sample generated next
+    pub sample: u64,
+    pub kind: DspEventKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DspEventKind {
+    // every write to DSP register through $F3, emitted before the events below
+    RegisterWrite { addr: u8, data: u8 },
+    // for each bit set in KON write
+    KeyOn { voice: usize },
+    // for each bit set in KOFF write
+    KeyOff { voice: usize },
+    // SRCN register is changed
+    SourceChange { voice: usize, srcn: u8 },
+    // 14bit pitch register is changed
+    PitchChange { voice: usize, pitch: u16 },
+    // ENDX bit of the voice is set by reaching BRR end block
+    VoiceEnd { voice: usize },
+}
+
+pub type EventCallback = Box<dyn FnMut(&DspEvent) + Send>;
+
+pub(crate) fn voices(bits: u8) -> impl Iterator<Item = usize> {
+    (0..8).filter(move |idx| (bits & (1 << idx)) != 0)
+}
@@ -1,5 +1,42 @@
 mod processor;
 mod dsp;
+mod error;
+mod spc_file;
+mod archive;
+mod spc2;
+mod song_end;
+mod post_mix;
+mod xid6;
+mod loudness;
+mod output_filter;
+mod event;
+mod midi;
+mod xm;
+mod sf2;
+mod synth;
+mod dsp_log;
+mod driver;
+mod timing;
+#[cfg(test)]
+mod testing;
+
+pub use error::Spc700Error;
+pub use archive::{SpcArchive, ArchiveEntry};
+pub use spc_file::Id666;
+pub use spc2::{Spc2File, Spc2Song};
+pub use song_end::{SongEnd, SongEndOptions};
+pub use post_mix::{PostMix, FadeOut, FadeCurve};
+pub use loudness::{Loudness, LoudnessMeter, read_sidecar_gain};
+pub use output_filter::OutputFilter;
+pub use dsp::{DspState, VoiceState, ADSRMode, BrrSample, Audition};
+pub use event::{DspEvent, DspEventKind, EventCallback};
+pub use midi::{MidiOptions, MidiRecorder};
+pub use xm::XmRecorder;
+pub use sf2::{Sf2Recorder, SpcInstrument};
+pub use synth::{SpcSynth, MidiInstrument};
+pub use dsp_log::{DspLog, DspWrite, DspReplay};
+pub use driver::{SoundDriver, detect_sound_driver};
+pub use timing::{SAMPLE_RATE, duration_to_samples, samples_to_duration};
 
 pub type SPC700 = processor::Spc700;
 
@@ -0,0 +1,290 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path;
+
+use crate::error::{Result, Spc700Error};
+use crate::xid6::{self, Xid6, AMPLIFICATION, AMPLIFICATION_RANGE};
+use crate::timing::SAMPLE_RATE;
+
+// gating block is 400ms and overlaps 75%, so blocks are made from 100ms sub-blocks.
+const SUB_BLOCK_SAMPLES: usize = 3200;
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+// ReplayGain 2.0 reference level
+const REPLAY_GAIN_REFERENCE_LUFS: f64 = -18.0;
+
+// true peak is measured on 4x oversampled signal
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+// Loudness measured according to EBU R128 (ITU-R BS.1770).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    // negative infinity if the whole track is silent
+    pub integrated_lufs: f64,
+    pub true_peak_dbtp: f64,
+}
+
+impl Loudness {
+    pub fn replay_gain_db(&self) -> f64 {
+        if self.integrated_lufs.is_finite() { REPLAY_GAIN_REFERENCE_LUFS - self.integrated_lufs } else { 0.0 }
+    }
+
+    // linear true peak, 1.0 is full scale
+    pub fn replay_gain_peak(&self) -> f64 {
+        10f64.powf(self.true_peak_dbtp / 20.0)
+    }
+
+    // Sidecar is a text file with a tag per line.
+    pub fn to_sidecar(&self) -> String {
+        format!(
+            "REPLAYGAIN_TRACK_GAIN={:.2} dB\nREPLAYGAIN_TRACK_PEAK={:.6}\nREPLAYGAIN_REFERENCE_LOUDNESS={:.1} LUFS\nR128_INTEGRATED_LOUDNESS={:.2} LUFS\nR128_TRUE_PEAK={:.2} dBTP\n",
+            self.replay_gain_db(),
+            self.replay_gain_peak(),
+            REPLAY_GAIN_REFERENCE_LUFS,
+            self.integrated_lufs,
+            self.true_peak_dbtp,
+        )
+    }
+
+    pub fn save_sidecar(&self, p: &path::Path) -> Result<()> {
+        fs::write(p, self.to_sidecar())?;
+        Ok(())
+    }
+
+    // Stores ReplayGain into xid6 amplification of SPC file `spc`, and returns the new file.
+    // Other xid6 items are kept.
+    pub fn tag_spc(&self, spc: &[u8]) -> Result<Vec<u8>> {
+        if !spc.starts_with(b"SNES-SPC700") {
+            return Err(Spc700Error::BadHeader("signature is not found".to_string()));
+        }
+
+        // gain out of the range of xid6 is saturated
+        let amplification = (10f64.powf(self.replay_gain_db() / 20.0) * 65536.0).round()
+            .clamp(*AMPLIFICATION_RANGE.start() as f64, *AMPLIFICATION_RANGE.end() as f64);
+        let mut xid6 = Xid6::parse(spc).unwrap_or_else(Xid6::new);
+        xid6.set_integer(AMPLIFICATION, amplification as u32);
+
+        Ok(xid6::replace_xid6(spc, &xid6))
+    }
+}
+
+// Reads track gain in dB from sidecar written by `Loudness::save_sidecar`.
+pub fn read_sidecar_gain(p: &path::Path) -> Result<Option<f64>> {
+    let text = fs::read_to_string(p)?;
+    let gain = text.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("REPLAYGAIN_TRACK_GAIN"))
+        .and_then(|(_, value)| value.trim().trim_end_matches("dB").trim().parse().ok());
+
+    Ok(gain)
+}
+
+// Accumulates interleaved stereo samples and measures their loudness.
+pub struct LoudnessMeter {
+    filters: [KWeighting; 2],
+    oversamplers: [Oversampler; 2],
+    sub_block_power: f64,
+    sub_block_len: usize,
+    sub_blocks: Vec<f64>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> LoudnessMeter {
+        LoudnessMeter {
+            filters: [KWeighting::new(), KWeighting::new()],
+            oversamplers: [Oversampler::new(), Oversampler::new()],
+            sub_block_power: 0.0,
+            sub_block_len: 0,
+            sub_blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn add_frames(&mut self, frames: &[i16]) {
+        for frame in frames.chunks_exact(2) {
+            for ((&sample, filter), oversampler) in frame.iter().zip(self.filters.iter_mut()).zip(self.oversamplers.iter_mut()) {
+                let sample = sample as f64 / 32768.0;
+                let weighted = filter.process(sample);
+                self.sub_block_power += weighted * weighted;
+                self.peak = self.peak.max(oversampler.peak(sample));
+            }
+
+            self.sub_block_len += 1;
+            if self.sub_block_len == SUB_BLOCK_SAMPLES {
+                self.sub_blocks.push(self.sub_block_power / SUB_BLOCK_SAMPLES as f64);
+                self.sub_block_power = 0.0;
+                self.sub_block_len = 0;
+            }
+        }
+    }
+
+    pub fn result(&self) -> Loudness {
+        let blocks = self.sub_blocks.windows(SUB_BLOCKS_PER_BLOCK)
+            .map(|window| window.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64)
+            .collect::<Vec<_>>();
+
+        let gated_mean = |threshold: f64| -> Option<f64> {
+            let gated = blocks.iter().filter(|&&power| to_lufs(power) > threshold).collect::<Vec<_>>();
+            if gated.is_empty() { None } else { Some(gated.iter().copied().sum::<f64>() / gated.len() as f64) }
+        };
+
+        let integrated_lufs = gated_mean(ABSOLUTE_GATE_LUFS)
+            .and_then(|power| gated_mean(to_lufs(power) + RELATIVE_GATE_LU))
+            .map(to_lufs)
+            .unwrap_or(f64::NEG_INFINITY);
+
+        Loudness {
+            integrated_lufs,
+            true_peak_dbtp: 20.0 * self.peak.log10(),
+        }
+    }
+}
+
+impl Default for LoudnessMeter {
+    fn default() -> LoudnessMeter {
+        LoudnessMeter::new()
+    }
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.z[0];
+        self.z[0] = self.b[1] * input - self.a[1] * output + self.z[1];
+        self.z[1] = self.b[2] * input - self.a[2] * output;
+        output
+    }
+}
+
+// Pre-filter (high shelf) and RLB filter (high-pass) of BS.1770,
+// derived from their analog prototypes for 32kHz.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new() -> KWeighting {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / SAMPLE_RATE as f64).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / SAMPLE_RATE as f64).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(input))
+    }
+}
+
+// Polyphase windowed sinc interpolator to find inter-sample peaks.
+struct Oversampler {
+    phases: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    history: [f64; TAPS_PER_PHASE],
+    pos: usize,
+}
+
+impl Oversampler {
+    fn new() -> Oversampler {
+        let taps = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (taps - 1) as f64 / 2.0;
+        let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for n in 0..taps {
+            let t = (n as f64 - center) / OVERSAMPLING as f64;
+            let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+            let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / taps as f64).cos();
+            phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * window;
+        }
+
+        // normalize each phase to keep DC level
+        for phase in phases.iter_mut() {
+            let sum = phase.iter().sum::<f64>();
+            phase.iter_mut().for_each(|coeff| *coeff /= sum);
+        }
+
+        Oversampler { phases, history: [0.0; TAPS_PER_PHASE], pos: 0 }
+    }
+
+    // Returns the largest absolute value among interpolated samples.
+    fn peak(&mut self, input: f64) -> f64 {
+        self.pos = (self.pos + 1) % TAPS_PER_PHASE;
+        self.history[self.pos] = input;
+
+        self.phases.iter()
+            .map(|phase| {
+                phase.iter().enumerate()
+                    .map(|(k, coeff)| coeff * self.history[(self.pos + TAPS_PER_PHASE - k) % TAPS_PER_PHASE])
+                    .sum::<f64>()
+                    .abs()
+            })
+            .fold(input.abs(), f64::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{spc_file, IDLE};
+
+    // 20 seconds of 1kHz sine at `dbfs` in both channels
+    fn sine_frames(dbfs: f64) -> Vec<i16> {
+        let amplitude = 10f64.powf(dbfs / 20.0) * 32768.0;
+        (0..20 * SAMPLE_RATE as usize)
+            .map(|n| (amplitude * (2.0 * PI * 1000.0 * n as f64 / SAMPLE_RATE as f64).sin()).round() as i16)
+            .flat_map(|sample| [sample, sample])
+            .collect()
+    }
+
+    // EBU Tech 3341, test cases 1 and 2
+    #[test]
+    fn stereo_sine_reads_its_level() {
+        for dbfs in [-23.0, -33.0] {
+            let mut meter = LoudnessMeter::new();
+            meter.add_frames(&sine_frames(dbfs));
+            let loudness = meter.result();
+            assert!((loudness.integrated_lufs - dbfs).abs() <= 0.1, "{} LUFS for {} dBFS", loudness.integrated_lufs, dbfs);
+        }
+    }
+
+    #[test]
+    fn amplification_is_saturated_into_xid6_range() {
+        let spc = spc_file(IDLE, &[]);
+        for (integrated_lufs, expected) in [(-18.0, 0x10000), (-60.0, 0x80000), (0.0, 0x8000)] {
+            let loudness = Loudness { integrated_lufs, true_peak_dbtp: 0.0 };
+            let xid6 = Xid6::parse(&loudness.tag_spc(&spc).unwrap()).unwrap();
+            assert_eq!(xid6.integer(AMPLIFICATION), Some(expected));
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+
+use crate::dsp::DspState;
+use crate::error::{Result, Spc700Error};
+use crate::event::{DspEvent, DspEventKind};
+use crate::timing::SAMPLE_RATE;
+
+// tempo of exported file is fixed, so ticks are converted from sample index directly.
+const MICROS_PER_QUARTER: u32 = 500_000;
+const ORIGINAL_PITCH: f32 = 0x1000 as f32;
+const BEND_CENTER: i32 = 0x2000;
+
+#[derive(Clone, Debug)]
+pub struct MidiOptions {
+    // MIDI note played by pitch 0x1000 for each SRCN
+    pub base_notes: HashMap<u8, f32>,
+    // MIDI note played by pitch 0x1000 for SRCN not in `base_notes`
+    pub default_base_note: f32,
+    pub ticks_per_quarter: u16,
+    // in semitones, written into each track by RPN 0
+    pub pitch_bend_range: u8,
+}
+
+impl Default for MidiOptions {
+    fn default() -> MidiOptions {
+        MidiOptions {
+            base_notes: HashMap::new(),
+            // middle C
+            default_base_note: 60.0,
+            ticks_per_quarter: 480,
+            pitch_bend_range: 12,
+        }
+    }
+}
+
+impl MidiOptions {
+    // Note number in semitones, including fraction, played by `pitch` of `srcn`.
+    fn note(&self, srcn: u8, pitch: u16) -> f32 {
+        let base = self.base_notes.get(&srcn).copied().unwrap_or(self.default_base_note);
+        base + 12.0 * (pitch.max(1) as f32 / ORIGINAL_PITCH).log2()
+    }
+}
+
+struct MidiEvent {
+    sample: u64,
+    message: Vec<u8>,
+}
+
+struct Note {
+    key: u8,
+    // index of note on event, whose velocity is fixed at note off
+    event_idx: usize,
+    volume: f32,
+    peak_level: i16,
+    bend: i32,
+}
+
+#[derive(Default)]
+struct VoiceTrack {
+    events: Vec<MidiEvent>,
+    note: Option<Note>,
+    program: Option<u8>,
+    pan: Option<u8>,
+    // voice is keyed on in the current sample, and note starts after its registers are settled
+    key_on: bool,
+}
+
+// Transcribes DSP events and states into a standard MIDI file.
+// Each voice is written into its own track and channel, and SRCN is used as program number.
+pub struct MidiRecorder {
+    options: MidiOptions,
+    voices: [VoiceTrack; 8],
+}
+
+impl MidiRecorder {
+    pub fn new(options: MidiOptions) -> MidiRecorder {
+        MidiRecorder { options, voices: Default::default() }
+    }
+
+    // `events` are emitted while `sample` is generated, and `state` is taken after that.
+    pub fn process(&mut self, sample: u64, events: &[DspEvent], state: &DspState) {
+        for event in events {
+            if let DspEventKind::KeyOn { voice } = event.kind {
+                self.note_off(voice, sample);
+                self.voices[voice].key_on = true;
+            }
+        }
+
+        for (idx, voice) in state.voices.iter().enumerate() {
+            if std::mem::take(&mut self.voices[idx].key_on) {
+                self.note_on(idx, sample, state);
+                continue;
+            }
+
+            let (key, peak_level, last_bend) = match &self.voices[idx].note {
+                Some(note) => (note.key, note.peak_level, note.bend),
+                None => continue,
+            };
+
+            let is_silent = peak_level > 0 && voice.envelope_level == 0;
+            if !voice.key_on || is_silent {
+                self.note_off(idx, sample);
+                continue;
+            }
+
+            let bend = self.bend(voice.srcn, voice.pitch, key);
+            if let Some(note) = &mut self.voices[idx].note {
+                note.peak_level = peak_level.max(voice.envelope_level);
+                note.bend = bend;
+            }
+            if bend != last_bend {
+                self.push(idx, sample, pitch_bend(idx, bend));
+            }
+            self.update_pan(idx, sample, voice.volume_left, voice.volume_right);
+        }
+    }
+
+    // Closes sounding notes at `end`, and returns the standard MIDI file of format 1.
+    pub fn finish(mut self, end: u64) -> Vec<u8> {
+        (0..8).for_each(|idx| self.note_off(idx, end));
+
+        let mut tempo = vec![0xFF, 0x51, 0x03];
+        tempo.extend_from_slice(&MICROS_PER_QUARTER.to_be_bytes()[1..]);
+        let conductor = vec![MidiEvent { sample: 0, message: tempo }];
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(self.voices.len() as u16 + 1).to_be_bytes());
+        bytes.extend_from_slice(&self.options.ticks_per_quarter.to_be_bytes());
+
+        write_track(&mut bytes, &conductor, self.ticks_per_sample());
+        for (idx, voice) in self.voices.iter().enumerate() {
+            let mut events = track_header(idx, self.options.pitch_bend_range);
+            events.extend(voice.events.iter().map(|event| MidiEvent { sample: event.sample, message: event.message.clone() }));
+            write_track(&mut bytes, &events, self.ticks_per_sample());
+        }
+
+        bytes
+    }
+
+    fn ticks_per_sample(&self) -> f64 {
+        self.options.ticks_per_quarter as f64 * 1_000_000.0 / MICROS_PER_QUARTER as f64 / SAMPLE_RATE as f64
+    }
+
+    fn note_on(&mut self, idx: usize, sample: u64, state: &DspState) {
+        let voice = &state.voices[idx];
+        let key = self.options.note(voice.srcn, voice.pitch).round().clamp(0.0, 127.0) as u8;
+        let bend = self.bend(voice.srcn, voice.pitch, key);
+        let channel = idx as u8;
+
+        if self.voices[idx].program != Some(voice.srcn & 0x7F) {
+            self.voices[idx].program = Some(voice.srcn & 0x7F);
+            self.push(idx, sample, vec![0xC0 | channel, voice.srcn & 0x7F]);
+        }
+        self.update_pan(idx, sample, voice.volume_left, voice.volume_right);
+        self.push(idx, sample, pitch_bend(idx, bend));
+        self.push(idx, sample, vec![0x90 | channel, key, 0]);
+
+        let volume = (voice.volume_left as i32).abs().max((voice.volume_right as i32).abs()) as f32 / 128.0;
+        let event_idx = self.voices[idx].events.len() - 1;
+        self.voices[idx].note = Some(Note { key, event_idx, volume, peak_level: voice.envelope_level, bend });
+    }
+
+    fn note_off(&mut self, idx: usize, sample: u64) {
+        let note = match self.voices[idx].note.take() {
+            Some(note) => note,
+            None => return,
+        };
+
+        // velocity is made from voice volume and the peak of envelope
+        let level = note.peak_level as f32 / 0x7FF as f32;
+        let velocity = (127.0 * (note.volume * level).sqrt()).round().clamp(1.0, 127.0) as u8;
+        self.voices[idx].events[note.event_idx].message[2] = velocity;
+
+        self.push(idx, sample, vec![0x80 | idx as u8, note.key, 0]);
+    }
+
+    // Pan is made from the balance of absolute volumes, because negative volume only inverts phase.
+    fn update_pan(&mut self, idx: usize, sample: u64, left: i8, right: i8) {
+        let left = (left as i32).abs();
+        let right = (right as i32).abs();
+        if left + right == 0 {
+            return;
+        }
+
+        let pan = (64 + 63 * (right - left) / (left + right)) as u8;
+        if self.voices[idx].pan != Some(pan) {
+            self.voices[idx].pan = Some(pan);
+            self.push(idx, sample, vec![0xB0 | idx as u8, 10, pan]);
+        }
+    }
+
+    // 14bit pitch bend value of `pitch` against `key`, clamped at bend range.
+    fn bend(&self, srcn: u8, pitch: u16, key: u8) -> i32 {
+        let range = self.options.pitch_bend_range.max(1) as f32;
+        let offset = (self.options.note(srcn, pitch) - key as f32) / range;
+        (BEND_CENTER + (offset * BEND_CENTER as f32).round() as i32).clamp(0, 0x3FFF)
+    }
+
+    fn push(&mut self, idx: usize, sample: u64, message: Vec<u8>) {
+        self.voices[idx].events.push(MidiEvent { sample, message });
+    }
+}
+
+fn pitch_bend(idx: usize, bend: i32) -> Vec<u8> {
+    vec![0xE0 | idx as u8, (bend & 0x7F) as u8, ((bend >> 7) & 0x7F) as u8]
+}
+
+// Track name and pitch bend range by RPN 0.
+fn track_header(idx: usize, bend_range: u8) -> Vec<MidiEvent> {
+    let name = format!("Voice {}", idx);
+    let mut meta = vec![0xFF, 0x03];
+    write_variable_length(&mut meta, name.len() as u32);
+    meta.extend_from_slice(name.as_bytes());
+
+    let channel = 0xB0 | idx as u8;
+    [
+        meta,
+        vec![channel, 101, 0],
+        vec![channel, 100, 0],
+        vec![channel, 6, bend_range.min(127)],
+        vec![channel, 38, 0],
+    ].into_iter().map(|message| MidiEvent { sample: 0, message }).collect()
+}
+
+fn write_track(bytes: &mut Vec<u8>, events: &[MidiEvent], ticks_per_sample: f64) {
+    let mut body = Vec::new();
+    let mut last_tick = 0;
+    for event in events {
+        let tick = (event.sample as f64 * ticks_per_sample).round() as u32;
+        write_variable_length(&mut body, tick - last_tick);
+        body.extend_from_slice(&event.message);
+        last_tick = tick;
+    }
+    body.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&body);
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.extend(groups.iter().rev());
+}
+
+// Channel message of a MIDI file at `seconds` from the beginning.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TimedMessage {
+    pub seconds: f64,
+    pub message: ChannelMessage,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChannelMessage {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    // note on with zero velocity is also converted into this
+    NoteOff { channel: u8, key: u8 },
+    Control { channel: u8, control: u8, value: u8 },
+    Program { channel: u8, program: u8 },
+    // -0x2000..0x2000
+    PitchBend { channel: u8, value: i16 },
+}
+
+enum TrackEvent {
+    // microseconds per quarter note
+    Tempo(u32),
+    Message(ChannelMessage),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self.bytes.get(self.pos..self.pos + len)
+            .ok_or(Spc700Error::TruncatedFile { expected: self.pos + len, actual: self.bytes.len() })?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let data = self.take(2)?;
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let data = self.take(4)?;
+        Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if (byte & 0x80) == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Spc700Error::InvalidData("too long variable length quantity in MIDI file".to_string()))
+    }
+
+    fn is_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+}
+
+// Parses standard MIDI file of format 0 or 1, and returns channel messages of all tracks in time order.
+pub(crate) fn parse_midi(bytes: &[u8]) -> Result<Vec<TimedMessage>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(Spc700Error::BadHeader("MThd is not found".to_string()));
+    }
+    let header_len = reader.u32()? as usize;
+    if header_len < 6 {
+        return Err(Spc700Error::BadHeader("MIDI header is too short".to_string()));
+    }
+    let _format = reader.u16()?;
+    let tracks = reader.u16()?;
+    let division = reader.u16()?;
+    reader.take(header_len - 6)?;
+
+    // events of all tracks are merged and sorted stably, so tempo in the first track comes first at the same tick
+    let mut events = Vec::new();
+    for _ in 0..tracks {
+        let id = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.take(len)?;
+        if id == b"MTrk" {
+            parse_track(chunk, &mut events)?;
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // SMPTE division has negative frames per second in the upper byte
+    let smpte_tick = match (division >> 8) as i8 {
+        fps if fps < 0 => Some(1.0 / (-(fps as f64) * (division & 0xFF) as f64)),
+        _ => None,
+    };
+    let mut seconds_per_tick = 0.5 / division.max(1) as f64;
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+    let mut messages = Vec::new();
+    for (tick, event) in events {
+        seconds += (tick - last_tick) as f64 * smpte_tick.unwrap_or(seconds_per_tick);
+        last_tick = tick;
+
+        match event {
+            TrackEvent::Tempo(tempo) => seconds_per_tick = tempo as f64 / 1_000_000.0 / division.max(1) as f64,
+            TrackEvent::Message(message) => messages.push(TimedMessage { seconds, message }),
+        }
+    }
+
+    Ok(messages)
+}
+
+fn parse_track(chunk: &[u8], events: &mut Vec<(u64, TrackEvent)>) -> Result<()> {
+    let mut reader = Reader { bytes: chunk, pos: 0 };
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while !reader.is_end() {
+        tick += reader.variable_length()? as u64;
+
+        let byte = reader.u8()?;
+        let (status, first) = match byte {
+            0x00..=0x7F => match running_status {
+                Some(status) => (status, byte),
+                None => return Err(Spc700Error::InvalidData("data byte without status in MIDI track".to_string())),
+            },
+            0x80..=0xEF => {
+                running_status = Some(byte);
+                (byte, reader.u8()?)
+            }
+            0xFF => {
+                let kind = reader.u8()?;
+                let len = reader.variable_length()? as usize;
+                let data = reader.take(len)?;
+                match kind {
+                    0x2F => break,
+                    0x51 if len == 3 => events.push((tick, TrackEvent::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])))),
+                    _ => (),
+                }
+                continue;
+            }
+            0xF0 | 0xF7 => {
+                let len = reader.variable_length()? as usize;
+                reader.take(len)?;
+                continue;
+            }
+            _ => return Err(Spc700Error::InvalidData(format!("unexpected status {:#04x} in MIDI track", byte))),
+        };
+
+        let channel = status & 0x0F;
+        let message = match status & 0xF0 {
+            0x80 => { reader.u8()?; ChannelMessage::NoteOff { channel, key: first } }
+            0x90 => match reader.u8()? {
+                0 => ChannelMessage::NoteOff { channel, key: first },
+                velocity => ChannelMessage::NoteOn { channel, key: first, velocity },
+            },
+            0xA0 => { reader.u8()?; continue; }
+            0xB0 => ChannelMessage::Control { channel, control: first, value: reader.u8()? },
+            0xC0 => ChannelMessage::Program { channel, program: first },
+            0xD0 => continue,
+            _ => {
+                let upper = reader.u8()? as i16;
+                ChannelMessage::PitchBend { channel, value: ((upper << 7) | first as i16) - 0x2000 }
+            }
+        };
+        events.push((tick, TrackEvent::Message(message)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::VoiceState;
+    use crate::testing::{dsp_state, voice_state};
+
+    fn voice(key_on: bool, pitch: u16, envelope_level: i16) -> VoiceState {
+        voice_state(key_on, 3, pitch, envelope_level)
+    }
+
+    fn state(voice0: VoiceState) -> DspState {
+        dsp_state(&[voice0])
+    }
+
+    fn key_on(sample: u64) -> DspEvent {
+        DspEvent { cycle: sample * 32, sample, kind: DspEventKind::KeyOn { voice: 0 } }
+    }
+
+    // Channel messages of voice 0 after the bend range set by the track header.
+    fn voice0_messages(bytes: &[u8]) -> Vec<TimedMessage> {
+        let messages = parse_midi(bytes).unwrap();
+        let voice0 = messages.into_iter().filter(|msg| match msg.message {
+            ChannelMessage::NoteOn { channel, .. } | ChannelMessage::NoteOff { channel, .. }
+            | ChannelMessage::Control { channel, .. } | ChannelMessage::Program { channel, .. }
+            | ChannelMessage::PitchBend { channel, .. } => channel == 0,
+        });
+        voice0.skip(4).collect()
+    }
+
+    // a semitone above 0x1000, rounded down from 0x10F3.B
+    const SEMITONE_UP: u16 = 0x10F3;
+
+    #[test]
+    fn transcribes_note_bend_and_velocity() {
+        let mut recorder = MidiRecorder::new(MidiOptions::default());
+        recorder.process(0, &[key_on(0)], &state(voice(true, 0x1000, 0x400)));
+        // envelope peaks after note on, and pitch goes up a semitone
+        recorder.process(3200, &[], &state(voice(true, SEMITONE_UP, 0x7FF)));
+        recorder.process(6400, &[], &state(voice(false, SEMITONE_UP, 0x300)));
+        let bytes = recorder.finish(9600);
+
+        // volume 64 / 128 and peak level 0x7FF give velocity 127 * sqrt(0.5)
+        let expected = [
+            (0.0, ChannelMessage::Program { channel: 0, program: 3 }),
+            (0.0, ChannelMessage::Control { channel: 0, control: 10, value: 64 }),
+            (0.0, ChannelMessage::PitchBend { channel: 0, value: 0 }),
+            (0.0, ChannelMessage::NoteOn { channel: 0, key: 60, velocity: 90 }),
+            // a little less than a semitone of the 12 semitones range (0x2000 / 12)
+            (0.1, ChannelMessage::PitchBend { channel: 0, value: 681 }),
+            (0.2, ChannelMessage::NoteOff { channel: 0, key: 60 }),
+        ];
+        let messages = voice0_messages(&bytes);
+        assert_eq!(messages.len(), expected.len());
+        for (msg, &(seconds, message)) in messages.iter().zip(expected.iter()) {
+            assert!((msg.seconds - seconds).abs() < 1e-9, "{:?}", msg);
+            assert_eq!(msg.message, message);
+        }
+    }
+
+    #[test]
+    fn retrigger_and_silence_end_notes() {
+        let mut recorder = MidiRecorder::new(MidiOptions::default());
+        recorder.process(0, &[key_on(0)], &state(voice(true, 0x1000, 0x7FF)));
+        recorder.process(3200, &[key_on(3200)], &state(voice(true, 0x2000, 0x7FF)));
+        // envelope reaches 0 while the key is still on
+        recorder.process(6400, &[], &state(voice(true, 0x2000, 0)));
+        let bytes = recorder.finish(9600);
+
+        let notes: Vec<_> = voice0_messages(&bytes).into_iter()
+            .filter(|msg| matches!(msg.message, ChannelMessage::NoteOn { .. } | ChannelMessage::NoteOff { .. }))
+            .map(|msg| ((msg.seconds * 10.0).round() as u32, msg.message))
+            .collect();
+        assert_eq!(notes, [
+            (0, ChannelMessage::NoteOn { channel: 0, key: 60, velocity: 90 }),
+            (1, ChannelMessage::NoteOff { channel: 0, key: 60 }),
+            (1, ChannelMessage::NoteOn { channel: 0, key: 72, velocity: 90 }),
+            (2, ChannelMessage::NoteOff { channel: 0, key: 72 }),
+        ]);
+    }
+
+    // Standard MIDI file of format 1 with `tracks`, which must end by themselves.
+    fn smf(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        bytes
+    }
+
+    fn timed(messages: &[TimedMessage]) -> Vec<(f64, ChannelMessage)> {
+        messages.iter().map(|msg| (msg.seconds, msg.message)).collect()
+    }
+
+    #[test]
+    fn parses_running_status_and_zero_velocity() {
+        let track = [
+            0x00, 0x90, 0x3C, 0x40,
+            // running status, and note on of velocity 0 as note off
+            0x60, 0x3E, 0x50,
+            0x60, 0x3C, 0x00,
+            0x00, 0xE1, 0x00, 0x40,
+            0x00, 0x00, 0x7F,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let messages = parse_midi(&smf(96, &[&track])).unwrap();
+
+        // 96 ticks per quarter note at the default 120 BPM
+        assert_eq!(timed(&messages), [
+            (0.0, ChannelMessage::NoteOn { channel: 0, key: 60, velocity: 64 }),
+            (0.5, ChannelMessage::NoteOn { channel: 0, key: 62, velocity: 80 }),
+            (1.0, ChannelMessage::NoteOff { channel: 0, key: 60 }),
+            (1.0, ChannelMessage::PitchBend { channel: 1, value: 0 }),
+            (1.0, ChannelMessage::PitchBend { channel: 1, value: -0x2000 + 0x3F80 }),
+        ]);
+    }
+
+    #[test]
+    fn tempo_changes_apply_to_all_tracks() {
+        let conductor = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40,
+            0x60, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let notes = [
+            0x60, 0x92, 0x3C, 0x40,
+            0x60, 0x82, 0x3C, 0x00,
+            0x60, 0x92, 0x3C, 0x40,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let messages = parse_midi(&smf(96, &[&conductor, &notes])).unwrap();
+
+        // a quarter note is 1 second at 60 BPM and 0.5 seconds after tempo changes to 120 BPM
+        assert_eq!(timed(&messages), [
+            (1.0, ChannelMessage::NoteOn { channel: 2, key: 60, velocity: 64 }),
+            (1.5, ChannelMessage::NoteOff { channel: 2, key: 60 }),
+            (2.0, ChannelMessage::NoteOn { channel: 2, key: 60, velocity: 64 }),
+        ]);
+    }
+
+    #[test]
+    fn rejects_truncated_chunks() {
+        let track = [0x00, 0x90, 0x3C, 0x40, 0x00, 0xFF, 0x2F, 0x00];
+        let bytes = smf(96, &[&track]);
+        assert!(parse_midi(&bytes).is_ok());
+
+        // chunk is shorter than its length, and the header is cut
+        for len in [bytes.len() - 1, 20, 10] {
+            let result = parse_midi(&bytes[..len]);
+            assert!(matches!(result, Err(Spc700Error::TruncatedFile { .. })), "{}: {:?}", len, result);
+        }
+        // event is cut at the end of the chunk
+        let result = parse_midi(&smf(96, &[&track[..3]]));
+        assert!(matches!(result, Err(Spc700Error::TruncatedFile { .. })), "{:?}", result);
+        // running status without a preceding status
+        let result = parse_midi(&smf(96, &[&[0x00, 0x3C, 0x40]]));
+        assert!(matches!(result, Err(Spc700Error::InvalidData(_))), "{:?}", result);
+        assert!(matches!(parse_midi(b"RIFF\0\0\0\x06"), Err(Spc700Error::BadHeader(_))));
+    }
+}
@@ -0,0 +1,120 @@
+use std::f32::consts::PI;
+use crate::timing::SAMPLE_RATE;
+
+// Generic analog output stage, not a model of any particular console revision:
+// a coupling capacitor blocking DC and a gentle treble roll-off after the DAC.
+// Corners are -3 dB points in Hz. They are round figures, not measured on hardware.
+const HIGH_PASS_CUTOFF: f32 = 5.0;
+const LOW_PASS_CUTOFF: f32 = 12000.0;
+
+// First order IIR filter designed by bilinear transform.
+#[derive(Clone, Copy)]
+struct OnePole {
+    b0: f32,
+    b1: f32,
+    a1: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl OnePole {
+    fn low_pass(cutoff: f32) -> OnePole {
+        let k = (PI * cutoff / SAMPLE_RATE as f32).tan();
+        let b0 = k / (1.0 + k);
+        OnePole { b0, b1: b0, a1: (k - 1.0) / (k + 1.0), x1: 0.0, y1: 0.0 }
+    }
+
+    fn high_pass(cutoff: f32) -> OnePole {
+        let k = (PI * cutoff / SAMPLE_RATE as f32).tan();
+        let b0 = 1.0 / (1.0 + k);
+        OnePole { b0, b1: -b0, a1: (k - 1.0) / (k + 1.0), x1: 0.0, y1: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.b1 * self.x1 - self.a1 * self.y1;
+        self.x1 = input;
+        self.y1 = output;
+        output
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.y1 = 0.0;
+    }
+}
+
+// DC blocking high-pass and treble roll-off of the analog output after DAC.
+#[derive(Clone)]
+pub struct OutputFilter {
+    // [left, right]
+    high_pass: [OnePole; 2],
+    low_pass: [OnePole; 2],
+}
+
+impl OutputFilter {
+    pub fn new() -> OutputFilter {
+        OutputFilter {
+            high_pass: [OnePole::high_pass(HIGH_PASS_CUTOFF); 2],
+            low_pass: [OnePole::low_pass(LOW_PASS_CUTOFF); 2],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.high_pass.iter_mut().chain(self.low_pass.iter_mut()).for_each(OnePole::reset);
+    }
+
+    pub(crate) fn process(&mut self, left: i16, right: i16) -> (i16, i16) {
+        let mut process = |idx: usize, value: i16| -> i16 {
+            let value = self.high_pass[idx].process(value as f32);
+            let value = self.low_pass[idx].process(value);
+            value.round().clamp(-32768.0, 32767.0) as i16
+        };
+
+        (process(0, left), process(1, right))
+    }
+}
+
+impl Default for OutputFilter {
+    fn default() -> OutputFilter {
+        OutputFilter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gain of the left output for a sine of `freq` Hz, measured by RMS after the filter settles.
+    fn gain(freq: f64) -> f32 {
+        let mut filter = OutputFilter::new();
+        let amplitude = 16000.0;
+        let samples = SAMPLE_RATE as usize * 4;
+        let (input, output) = (0..samples)
+            .map(|idx| {
+                let phase = (freq * idx as f64 / SAMPLE_RATE as f64).fract();
+                let value = (amplitude * (2.0 * std::f64::consts::PI * phase).sin()).round() as i16;
+                (value, filter.process(value, value).0)
+            })
+            .skip(samples / 2)
+            .fold((0.0, 0.0), |(input, output), (x, y)| (input + (x as f64).powi(2), output + (y as f64).powi(2)));
+        (output / input).sqrt() as f32
+    }
+
+    #[test]
+    fn corners_are_3db_down() {
+        for cutoff in [HIGH_PASS_CUTOFF, LOW_PASS_CUTOFF] {
+            let db = 20.0 * gain(cutoff as f64).log10();
+            assert!((db + 3.0).abs() < 0.2, "{} Hz: {} dB", cutoff, db);
+        }
+        assert!(gain(1000.0) > 0.95);
+    }
+
+    #[test]
+    fn dc_is_blocked() {
+        let mut filter = OutputFilter::new();
+        let (first, _) = filter.process(10000, 10000);
+        let last = (0..SAMPLE_RATE * 2).map(|_| filter.process(10000, 10000)).last().unwrap();
+        assert!(first > 5000);
+        assert_eq!(last, (0, 0));
+    }
+}
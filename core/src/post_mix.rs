@@ -0,0 +1,160 @@
+use std::time::Duration;
+use crate::timing::duration_to_samples;
+
+// logarithmic fade goes down to this level, and then becomes silent
+const LOG_FADE_FLOOR_DB: f32 = -60.0;
+// soft clipping starts above this level
+const SOFT_CLIP_KNEE: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeCurve {
+    Linear,
+    // linear in decibels
+    Logarithmic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FadeOut {
+    // position from the beginning of the song
+    pub start: Duration,
+    pub length: Duration,
+    pub curve: FadeCurve,
+}
+
+// Processing applied to DSP output samples.
+// Samples are amplified by `gain_db`, soft clipped if enabled, saturated into i16, and then faded out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostMix {
+    pub gain_db: f32,
+    pub soft_clip: bool,
+    pub fade: Option<FadeOut>,
+}
+
+impl Default for PostMix {
+    fn default() -> PostMix {
+        PostMix {
+            gain_db: 0.0,
+            soft_clip: false,
+            fade: None,
+        }
+    }
+}
+
+impl PostMix {
+    // `sample` is the index of the sample from the beginning of the song.
+    pub(crate) fn process(&self, sample: u64, left: i16, right: i16) -> (i16, i16) {
+        if self.gain_db == 0.0 && !self.soft_clip && self.fade.is_none() {
+            return (left, right);
+        }
+
+        let gain = db_to_gain(self.gain_db);
+        let fade_gain = self.fade_gain(sample);
+        let process = |value: i16| -> i16 {
+            let value = value as f32 / 32768.0 * gain;
+            let value = if self.soft_clip { soft_clip(value) } else { value };
+            let value = (value * 32768.0).round().clamp(-32768.0, 32767.0);
+            (value * fade_gain).round() as i16
+        };
+
+        (process(left), process(right))
+    }
+
+    fn fade_gain(&self, sample: u64) -> f32 {
+        let fade = match &self.fade {
+            Some(fade) => fade,
+            None => return 1.0,
+        };
+
+        let start = duration_to_samples(fade.start);
+        let length = duration_to_samples(fade.length);
+        if sample < start {
+            return 1.0;
+        }
+        if sample >= start + length {
+            return 0.0;
+        }
+
+        let progress = (sample - start) as f32 / length as f32;
+        match fade.curve {
+            FadeCurve::Linear => 1.0 - progress,
+            FadeCurve::Logarithmic => db_to_gain(LOG_FADE_FLOOR_DB * progress),
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Linear under the knee, and approaches to full scale smoothly above the knee.
+fn soft_clip(value: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= SOFT_CLIP_KNEE {
+        return value;
+    }
+
+    let headroom = 1.0 - SOFT_CLIP_KNEE;
+    let clipped = SOFT_CLIP_KNEE + headroom * ((magnitude - SOFT_CLIP_KNEE) / headroom).tanh();
+    clipped.copysign(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fade(curve: FadeCurve) -> PostMix {
+        PostMix {
+            fade: Some(FadeOut { start: Duration::from_secs(1), length: Duration::from_secs(1), curve }),
+            ..PostMix::default()
+        }
+    }
+
+    #[test]
+    fn gain_saturates_into_i16() {
+        let post_mix = PostMix { gain_db: 20.0 * 2f32.log10(), ..PostMix::default() };
+        assert_eq!(post_mix.process(0, 1000, -1000), (2000, -2000));
+        assert_eq!(post_mix.process(0, 20000, -20000), (32767, -32768));
+    }
+
+    #[test]
+    fn soft_clip_bends_above_knee() {
+        let post_mix = PostMix { soft_clip: true, ..PostMix::default() };
+        assert_eq!(post_mix.process(0, 8192, -8192), (8192, -8192));
+
+        let (loud, _) = post_mix.process(0, 24576, 0);
+        let (louder, _) = post_mix.process(0, 32767, 0);
+        assert!(16384 < loud && loud < louder && louder < 32767);
+    }
+
+    #[test]
+    fn linear_fade() {
+        let post_mix = fade(FadeCurve::Linear);
+        assert_eq!(post_mix.process(31999, 10000, -10000), (10000, -10000));
+        assert_eq!(post_mix.process(32000, 10000, -10000), (10000, -10000));
+        assert_eq!(post_mix.process(48000, 10000, -10000), (5000, -5000));
+        assert_eq!(post_mix.process(56000, 10000, -10000), (2500, -2500));
+    }
+
+    #[test]
+    fn logarithmic_fade() {
+        let post_mix = fade(FadeCurve::Logarithmic);
+        assert_eq!(post_mix.process(32000, 10000, 0), (10000, 0));
+        // half of -60dB
+        assert_eq!(post_mix.process(48000, 10000, 0), (316, 0));
+    }
+
+    #[test]
+    fn fade_past_its_end_is_silent() {
+        for curve in [FadeCurve::Linear, FadeCurve::Logarithmic] {
+            let post_mix = fade(curve);
+            assert_eq!(post_mix.process(64000, 32767, -32768), (0, 0));
+            assert_eq!(post_mix.process(1 << 40, 32767, -32768), (0, 0));
+        }
+    }
+
+    #[test]
+    fn fade_applies_after_saturation() {
+        let post_mix = PostMix { gain_db: 12.0, ..fade(FadeCurve::Linear) };
+        assert_eq!(post_mix.process(48000, 20000, -20000), (16384, -16384));
+    }
+}
@@ -2,26 +2,58 @@ pub(crate) mod ram;
 pub(crate) mod timer;
 pub(crate) mod register;
 
-extern crate spc;
-
 use ram::*;
 use register::*;
-use crate::dsp::DSP;
-use timer::Timer;
-
-use std::io::Result;
-use spc::spc::Spc;
-
+use crate::dsp::{DSP, DspState, PitchShift, Audition};
+use crate::error::{Result, Spc700Error};
+use crate::spc_file::{SpcFile, Id666};
+use crate::spc2::Spc2File;
+use crate::song_end::{SongEnd, SongEndOptions, SongEndDetector};
+use crate::post_mix::PostMix;
+use crate::output_filter::OutputFilter;
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::event::{DspEvent, DspEventKind, EventCallback};
+use crate::midi::{MidiOptions, MidiRecorder};
+use crate::xm::XmRecorder;
+use crate::sf2::Sf2Recorder;
+use crate::synth::SpcSynth;
+use crate::dsp_log::{DspLog, DspWrite};
+use crate::driver::{SoundDriver, detect_sound_driver};
+use crate::timing::{duration_to_samples, samples_to_duration};
+use timer::{Timer, TimerClock};
+
+use std::fs;
+use std::io::Read;
 use std::path;
+use std::time::Duration;
 
 use typenum::marker_traits::Unsigned;
 
+const TEMPO_ONE: u32 = 0x10000;
+const TEMPO_RANGE: (f32, f32) = (0.25, 4.0);
+
 pub struct Spc700 {
     pub reg: Register,
     timer: [Timer; 3],
     pub cycle_counter: u64,
+    // CPU cycles since the snapshot is loaded
     total_cycles: u64,
-    is_stopped: bool
+    is_stopped: bool,
+    fault: Option<Spc700Error>,
+    op_pc: u16,
+    tag: Option<Id666>,
+    // number of samples generated since the snapshot is loaded
+    samples: u64,
+    snapshot: Option<SpcFile>,
+    post_mix: PostMix,
+    output_filter: Option<OutputFilter>,
+    // DSP cycles per CPU cycle in 16.16 fixed point, and its fraction carried over
+    dsp_step: u32,
+    dsp_cycle_frac: u32,
+    transpose: f32,
+    unlimited_pitch: bool,
+    event_queue: Option<Vec<DspEvent>>,
+    event_callback: Option<EventCallback>,
 }
 
 
@@ -46,6 +78,9 @@ impl OperationResult<()> {
     }
 }
 
+type AluOp = fn(&mut Register, u8, u8) -> u8;
+type ShiftOp = fn(u8, bool) -> (u8, bool);
+
 const DECODE_TABLE: [fn(&mut Spc700, u8) -> OperationResult<()>; 256] = [
     // upper opcode: 0x0
     // lower opcode: 0x0
@@ -373,64 +408,458 @@ impl Spc700 {
     pub fn new() -> Spc700 {
         Spc700 {
             reg: Register::new(0),
-            timer: [Timer::new(8000), Timer::new(8000), Timer::new(64000)],            
+            timer: [Timer::new(TimerClock::Hz8000), Timer::new(TimerClock::Hz8000), Timer::new(TimerClock::Hz64000)],            
             cycle_counter: 0,
             total_cycles: 0,
             is_stopped: false,
+            fault: None,
+            op_pc: 0,
+            tag: None,
+            samples: 0,
+            snapshot: None,
+            post_mix: PostMix::default(),
+            output_filter: None,
+            dsp_step: TEMPO_ONE,
+            dsp_cycle_frac: 0,
+            transpose: 0.0,
+            unlimited_pitch: false,
+            event_queue: None,
+            event_callback: None,
         }
     }
 
     pub fn load(&mut self, p: &path::Path) -> Result<()> {
-        let spc = Spc::load(p)?;
-        Ram::init(&spc.ram, &spc.ipl_rom);
+        let bytes = fs::read(p)?;
+        self.load_from_bytes(&bytes)
+    }
+
+    pub fn load_from_reader(&mut self, mut reader: impl Read) -> Result<()> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        self.load_from_bytes(&bytes)
+    }
+
+    pub fn load_from_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let spc = SpcFile::parse(bytes)?;
+        self.load_from_spc_file(spc);
+
+        Ok(())
+    }
+
+    // Loads `idx`th song of SPC2 in the same way as SPC file.
+    pub fn load_from_spc2(&mut self, spc2: &Spc2File, idx: usize) -> Result<()> {
+        let spc = spc2.spc_file(idx)?;
+        self.load_from_spc_file(spc);
+
+        Ok(())
+    }
+
+    pub(crate) fn load_from_spc_file(&mut self, spc: SpcFile) {
+        self.init_by_snapshot(&spc);
+        self.snapshot = Some(spc);
+    }
+
+    fn init_by_snapshot(&mut self, spc: &SpcFile) {
+        Ram::init(&spc.ram, &spc.extra_ram);
         DSP::init(&spc.regs);
+        DSP::global().set_pitch_shift(PitchShift::with_semitones(self.transpose, self.unlimited_pitch));
+        DSP::global().set_record_events(self.is_recording_events());
 
         let divider0 = spc.ram[0x00FA];
         let divider1 = spc.ram[0x00FB];
         let divider2 = spc.ram[0x00FC];        
 
-        let mut timer: Vec<Timer> = [8000, 8000, 64000].iter()
+        let mut timer: Vec<Timer> = [TimerClock::Hz8000, TimerClock::Hz8000, TimerClock::Hz64000].iter()
             .zip([divider0, divider1, divider2].iter())
-            .map(|(&hz, &divider)| Timer::new_with_init(hz, divider, 0))
+            .zip(spc.ram[0x00FD..=0x00FF].iter())
+            .map(|((&clock, &divider), &out)| Timer::new_with_init(clock, divider, out))
             .collect();
 
+        // timers are already running in the snapshot, so counters must not be reset by enable().
         let control = spc.ram[0x00F1]; 
         timer.iter_mut().zip(0..3).for_each(|(timer, idx)| {
-            let enable = (control & (1 << idx)) > 0;
-            if enable {
-                timer.enable();
-            }
+            timer.enable = (control & (1 << idx)) > 0;
         });
-        let register = Register::new_with_init(&spc);
+        let register = Register::new_with_init(spc);
        
         self.reg = register;
         self.timer.copy_from_slice(&timer[..]);
+        self.is_stopped = false;
+        self.fault = None;
+        self.tag = spc.tag.clone();
+        self.samples = 0;
+        self.total_cycles = 0;
+        if let Some(filter) = &mut self.output_filter {
+            filter.reset();
+        }
+    }
+
+    // Restarts the loaded song from the beginning.
+    pub fn restart(&mut self) -> Result<()> {
+        let spc = self.snapshot.take()
+            .ok_or_else(|| Spc700Error::InvalidState("no song is loaded".to_string()))?;
+        self.init_by_snapshot(&spc);
+        self.snapshot = Some(spc);
 
         Ok(())
     }
 
+    // Playback position from the beginning of the song.
+    pub fn position(&self) -> Duration {
+        samples_to_duration(self.samples)
+    }
+
+    // Skips `duration` of the song.
+    // CPU and timers run as usual, but DSP only updates its internal state
+    // without interpolation, mixing and echo filtering, so this is much faster than rendering.
+    pub fn seek(&mut self, duration: Duration) {
+        let samples = duration_to_samples(duration);
+
+        DSP::global().set_output_enable(false);
+        (0..samples).for_each(|_| self.run_until_sample());
+        DSP::global().set_output_enable(true);
+    }
+
+    // Moves to `position` from the beginning of the song.
+    // If `position` is behind the current position, the song is restarted.
+    pub fn seek_to(&mut self, position: Duration) -> Result<()> {
+        if position < self.position() {
+            self.restart()?;
+        }
+
+        let duration = position - self.position();
+        self.seek(duration);
+
+        Ok(())
+    }
+
+    // ID666 tag of the loaded SPC if it has.
+    pub fn tag(&self) -> Option<&Id666> {
+        self.tag.as_ref()
+    }
+
+    // Identifies the sound driver in RAM of the loaded snapshot. None if no signature is found.
+    pub fn sound_driver(&self) -> Result<Option<SoundDriver>> {
+        let spc = self.snapshot.as_ref()
+            .ok_or_else(|| Spc700Error::InvalidState("no song is loaded".to_string()))?;
+
+        Ok(detect_sound_driver(&spc.ram[..]))
+    }
+
+    // If CPU is halted by an emulation fault, returns its cause.
+    pub fn fault(&self) -> Option<&Spc700Error> {
+        self.fault.as_ref()
+    }
+
+    pub fn post_mix(&self) -> &PostMix {
+        &self.post_mix
+    }
+
+    // Post mix is applied to samples from `next_sample`, `render` and `render_f32`.
+    pub fn set_post_mix(&mut self, post_mix: PostMix) {
+        self.post_mix = post_mix;
+    }
+
+    pub fn is_output_filter_enabled(&self) -> bool {
+        self.output_filter.is_some()
+    }
+
+    // Applies generic analog output filter before post mix. Disabled means raw DSP output.
+    pub fn set_output_filter(&mut self, enabled: bool) {
+        self.output_filter = if enabled { Some(OutputFilter::new()) } else { None };
+    }
+
+    pub fn tempo(&self) -> f32 {
+        TEMPO_ONE as f32 / self.dsp_step as f32
+    }
+
+    // Scales speed of CPU and timers against DSP sample generation,
+    // so sequencers run faster or slower without changing pitch.
+    // `tempo` is clamped into 0.25..=4.0.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        let tempo = if tempo.is_nan() { 1.0 } else { tempo.clamp(TEMPO_RANGE.0, TEMPO_RANGE.1) };
+        self.dsp_step = (TEMPO_ONE as f32 / tempo).round() as u32;
+    }
+
+    pub fn transpose(&self) -> f32 {
+        self.transpose
+    }
+
+    // Transposes all voices by `semitones` without changing tempo.
+    // Pitch registers read by the CPU are untouched.
+    // Effective pitch is clamped at 14bit hardware limit unless `unlimited` is true.
+    pub fn set_transpose(&mut self, semitones: f32, unlimited: bool) {
+        self.transpose = semitones;
+        self.unlimited_pitch = unlimited;
+        DSP::global().set_pitch_shift(PitchShift::with_semitones(semitones, unlimited));
+    }
+
+    // Snapshot of voices and global DSP state at the last generated sample.
+    pub fn dsp_state(&self) -> DspState {
+        DSP::global().state()
+    }
+
+    // Starts or stops queueing DSP events. Queued events are discarded when stopped.
+    pub fn set_event_queue(&mut self, enable: bool) {
+        self.event_queue = if enable { Some(self.event_queue.take().unwrap_or_default()) } else { None };
+        DSP::global().set_record_events(self.is_recording_events());
+    }
+
+    // Takes DSP events queued since the last call.
+    pub fn drain_events(&mut self) -> Vec<DspEvent> {
+        self.event_queue.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // `callback` is called for each DSP event as soon as it occurs. None removes the callback.
+    pub fn set_event_callback(&mut self, callback: Option<EventCallback>) {
+        self.event_callback = callback;
+        DSP::global().set_record_events(self.is_recording_events());
+    }
+
+    fn is_recording_events(&self) -> bool {
+        self.event_queue.is_some() || self.event_callback.is_some()
+    }
+
+    // Voice played directly from the host with samples in RAM of the loaded song.
+    pub fn audition(&self) -> Audition {
+        Audition::new()
+    }
+
+    // Plays standard MIDI file `midi` with samples in RAM of the loaded song.
+    // The song must be restarted to play it again after the synth is used.
+    pub fn synth(&self, midi: &[u8]) -> Result<SpcSynth> {
+        SpcSynth::new(midi)
+    }
+
     pub fn next_sample(&mut self) -> (i16, i16) {        
-        loop {
-            let before_cycle_count = DSP::global().sync_counter;
-            self.clock();
-            let after_cycle_count = DSP::global().sync_counter;
+        self.run_until_sample();
+        self.output()
+    }
+
+    // Fills `buffer` with interleaved stereo samples and returns the number of rendered frames.
+    // If `buffer` has odd length, the last element is filled by silence.
+    pub fn render(&mut self, buffer: &mut [i16]) -> usize {
+        let mut frames = buffer.chunks_exact_mut(2);
+        let mut count = 0;
+        for frame in &mut frames {
+            self.run_until_sample();
+            let (left, right) = self.output();
+            frame[0] = left;
+            frame[1] = right;
+            count += 1;
+        }
+        frames.into_remainder().fill(0);
+
+        count
+    }
+
+    // Same as `render`, but samples are normalized into -1.0..1.0.
+    pub fn render_f32(&mut self, buffer: &mut [f32]) -> usize {
+        const SCALE: f32 = 1.0 / 32768.0;
+
+        let mut frames = buffer.chunks_exact_mut(2);
+        let mut count = 0;
+        for frame in &mut frames {
+            self.run_until_sample();
+            let (left, right) = self.output();
+            frame[0] = left as f32 * SCALE;
+            frame[1] = right as f32 * SCALE;
+            count += 1;
+        }
+        frames.into_remainder().fill(0.0);
+
+        count
+    }
 
-            if before_cycle_count > after_cycle_count {
-                break;
+    // Plays the song from the beginning without output and detects where it loops or becomes silent.
+    // The song is restarted after detection. Returns None if nothing is detected until `max_duration`.
+    // DSP events are not emitted while detecting.
+    pub fn detect_song_end(&mut self, options: &SongEndOptions) -> Result<Option<SongEnd>> {
+        self.restart()?;
+        DSP::global().take_key_on();
+        DSP::global().set_record_events(false);
+
+        let max_samples = duration_to_samples(options.max_duration);
+        let mut detector = SongEndDetector::new(options.clone());
+        let mut song_end = None;
+        while song_end.is_none() && self.samples < max_samples && self.fault.is_none() {
+            let is_generated = self.clock();
+            let dsp = DSP::global();
+
+            let kon = dsp.take_key_on();
+            if kon != 0 {
+                song_end = detector.key_on(self.samples, kon);
             }
-        }        
 
-        (DSP::global().sample_left_out(), DSP::global().sample_right_out())
+            if is_generated {
+                self.samples += 1;
+                song_end = song_end.or_else(|| detector.update(self.samples, dsp.sample_left_out(), dsp.sample_right_out()));
+            }
+        }
+
+        self.restart()?;
+        Ok(song_end)
+    }
+
+    // Renders `duration` from the beginning without playing and measures its loudness.
+    // Post mix is applied, so fade out is taken into account.
+    // Fails if no gating block of 400ms is louder than the absolute gate.
+    // The song is restarted after analysis, and DSP events are not emitted while analyzing.
+    pub fn analyze_loudness(&mut self, duration: Duration) -> Result<Loudness> {
+        const BLOCK_FRAMES: usize = 1024;
+
+        self.restart()?;
+        DSP::global().set_record_events(false);
+
+        let mut meter = LoudnessMeter::new();
+        let mut block = [0; BLOCK_FRAMES * 2];
+        let mut remaining = duration_to_samples(duration);
+        while remaining > 0 && self.fault.is_none() {
+            let frames = remaining.min(BLOCK_FRAMES as u64) as usize;
+            self.render(&mut block[..frames * 2]);
+            meter.add_frames(&block[..frames * 2]);
+            remaining -= frames as u64;
+        }
+
+        self.restart()?;
+
+        // loudness of a part shorter than a gating block or silent is meaningless as ReplayGain
+        let loudness = meter.result();
+        if !loudness.integrated_lufs.is_finite() {
+            return Err(Spc700Error::InvalidState("no block passes the loudness gate, the track is too short or silent".to_string()));
+        }
+
+        Ok(loudness)
+    }
+
+    // Plays `duration` from the beginning without output and transcribes it into standard MIDI file.
+    // The song is restarted after export.
+    pub fn export_midi(&mut self, duration: Duration, options: &MidiOptions) -> Result<Vec<u8>> {
+        let mut recorder = MidiRecorder::new(options.clone());
+        let end = self.record(duration, |sample, events, state| recorder.process(sample, events, state))?;
+
+        Ok(recorder.finish(end))
+    }
+
+    // Plays `duration` from the beginning without output and transcribes it into XM module
+    // with BRR samples as instruments. The song is restarted after export.
+    pub fn export_xm(&mut self, duration: Duration) -> Result<Vec<u8>> {
+        let mut recorder = XmRecorder::new();
+        let end = self.record(duration, |sample, events, state| recorder.process(sample, events, state))?;
+        let title = self.tag.as_ref().map(|tag| tag.song_title.as_str()).unwrap_or("");
+
+        Ok(recorder.finish(end, title))
+    }
+
+    // Plays `duration` from the beginning without output and builds SoundFont 2
+    // from instruments keyed on during playback. The song is restarted after export.
+    pub fn export_sf2(&mut self, duration: Duration) -> Result<Vec<u8>> {
+        let mut recorder = Sf2Recorder::new();
+        self.record(duration, |sample, events, state| recorder.process(sample, events, state))?;
+        let name = self.tag.as_ref().map(|tag| tag.game_title.as_str()).unwrap_or("");
+
+        Ok(recorder.finish(name))
+    }
+
+    // Plays `duration` from the beginning without output, and logs initial RAM and DSP registers
+    // with all DSP writes, which can be replayed without CPU. The song is restarted after recording.
+    pub fn record_dsp_log(&mut self, duration: Duration) -> Result<DspLog> {
+        let spc = self.snapshot.as_ref()
+            .ok_or_else(|| Spc700Error::InvalidState("no song is loaded".to_string()))?;
+        let (ram, extra_ram, regs) = (spc.ram.clone(), spc.extra_ram, spc.regs);
+
+        let mut writes = Vec::new();
+        let samples = self.record(duration, |_, events, _| {
+            writes.extend(events.iter().filter_map(|event| match event.kind {
+                DspEventKind::RegisterWrite { addr, data } => Some(DspWrite { cycle: event.cycle, sample: event.sample, addr, data }),
+                _ => None,
+            }));
+        })?;
+
+        Ok(DspLog { ram, extra_ram, regs, writes, samples })
+    }
+
+    // Plays `duration` from the beginning without output, and passes DSP events and state of each sample to `process`.
+    // The song is restarted after recording, and returns the number of recorded samples.
+    fn record(&mut self, duration: Duration, mut process: impl FnMut(u64, &[DspEvent], &DspState)) -> Result<u64> {
+        self.restart()?;
+
+        // events are taken by `process`, so user's queue and callback are suspended
+        let queue = self.event_queue.replace(Vec::new());
+        let callback = self.event_callback.take();
+        let dsp = DSP::global();
+        dsp.set_record_events(true);
+        dsp.set_output_enable(false);
+
+        let samples = duration_to_samples(duration);
+        while self.samples < samples && self.fault.is_none() {
+            self.run_until_sample();
+            let events = self.drain_events();
+            process(self.samples - 1, &events, &dsp.state());
+        }
+        let end = self.samples;
+
+        dsp.set_output_enable(true);
+        self.event_queue = queue;
+        self.event_callback = callback;
+        self.restart()?;
+
+        Ok(end)
+    }
+
+    #[inline]
+    fn output(&mut self) -> (i16, i16) {
+        let dsp = DSP::global();
+        let (left, right) = match &mut self.output_filter {
+            Some(filter) => filter.process(dsp.sample_left_out(), dsp.sample_right_out()),
+            None => (dsp.sample_left_out(), dsp.sample_right_out()),
+        };
+
+        self.post_mix.process(self.samples - 1, left, right)
+    }
+
+    #[inline]
+    fn run_until_sample(&mut self) {
+        while !self.clock() {}
+        self.samples += 1;
     }
     
-    fn clock(&mut self) -> () {
+    // Executes one instruction and returns whether DSP generated a new sample.
+    fn clock(&mut self) -> bool {
+        let cycle = self.total_cycles;
+        let is_generated = self.step();
+        self.dispatch_events(cycle);
+
+        is_generated
+    }
+
+    // Events caused by an instruction are stamped by the cycle at its beginning.
+    #[inline]
+    fn dispatch_events(&mut self, cycle: u64) {
+        if !self.is_recording_events() {
+            return;
+        }
+
+        let sample = self.samples;
+        for kind in DSP::global().drain_events() {
+            let event = DspEvent { cycle, sample, kind };
+            if let Some(callback) = &mut self.event_callback {
+                callback(&event);
+            }
+            if let Some(queue) = &mut self.event_queue {
+                queue.push(event);
+            }
+        }
+    }
+
+    fn step(&mut self) -> bool {
         if self.is_stopped {
             self.count_cycles(2);
-            DSP::global().flush();
-            return;
+            return DSP::global().flush();
         }
 
         let pc = self.reg.inc_pc(1);
+        self.op_pc = pc;
         let OperationResult { ret: opcode, cycles: fetch_cycles } = self.read_ram(pc);                
         let instruction = DECODE_TABLE[opcode as usize];
         let OperationResult { ret: _, cycles: op_cycles } = instruction(self, opcode);
@@ -440,7 +869,7 @@ impl Spc700 {
         log::debug!("op: {:04x}, {}", opcode, &self.reg);
 
         self.count_cycles(cycles as u16);
-        DSP::global().flush();
+        DSP::global().flush()
     }
 
     fn mov_reg_imm(&mut self, opcode: u8) -> OperationResult<()> {
@@ -448,7 +877,7 @@ impl Spc700 {
             0x8D => 2,
             0xCD => 1, 
             0xE8 => 0,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: imm, cycles: read_cycles } = self.read_from_pc();
@@ -456,7 +885,7 @@ impl Spc700 {
             0 => self.reg.a = imm,
             1 => self.reg.x = imm,
             2 => self.reg.y = imm,
-            _ => panic!("register type must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         }
 
         self.set_mov_flag(imm);
@@ -475,7 +904,7 @@ impl Spc700 {
             (0xB, 0xD) => 1, 
             (0xD, 0xD) => 2,
             (0xF, 0xD) => 0,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let to = match (upper, lower) {
@@ -485,7 +914,7 @@ impl Spc700 {
             (0xB, 0xD) => 3,
             (0xD, 0xD) => 0,
             (0xF, 0xD) => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let data = match from {
@@ -493,7 +922,7 @@ impl Spc700 {
             1 => self.reg.x,
             2 => self.reg.y,
             3 => self.reg.sp,
-            _ => panic!("register type must be between 0 to 3"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         match to {
@@ -501,7 +930,7 @@ impl Spc700 {
             1 => self.reg.x = data,
             2 => self.reg.y = data,
             3 => self.reg.sp = data,
-            _ => panic!("register type must be between 0 to 3"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         self.set_mov_flag(data);
@@ -517,7 +946,7 @@ impl Spc700 {
             0xE4 => self.reg.a = data,
             0xF8 => self.reg.x = data,
             0xEB => self.reg.y = data,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         }
 
         self.set_mov_flag(data);
@@ -532,7 +961,7 @@ impl Spc700 {
         let reg_type = match opcode {
             0xF4 => 0,
             0xFB => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult { ret: addr, cycles: read_cycles } = self.read_from_pc();
@@ -542,7 +971,7 @@ impl Spc700 {
         match reg_type {
             0 => self.reg.a = data,
             2 => self.reg.y = data,
-            _ => panic!("register type must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         }
 
         self.set_mov_flag(data);
@@ -566,7 +995,7 @@ impl Spc700 {
             0xE5 => 0,
             0xE9 => 1,
             0xEC => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: lower, cycles: read_lower_cycles } = self.read_from_pc();
@@ -580,7 +1009,7 @@ impl Spc700 {
             0 => self.reg.a = data,
             1 => self.reg.x = data,
             2 => self.reg.y = data,
-            _ => panic!("register type must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         }
 
         self.set_mov_flag(data);
@@ -730,7 +1159,7 @@ impl Spc700 {
             0xC4 => 0,
             0xCB => 2,
             0xD8 => 1,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: addr, cycles: addr_cycles } = self.read_from_pc();
@@ -739,7 +1168,7 @@ impl Spc700 {
             0 => self.reg.a,
             1 => self.reg.x,
             2 => self.reg.y,
-            _ => panic!("register type must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: _, cycles: write_cycles } = self.write_to_page(addr, data);
@@ -751,7 +1180,7 @@ impl Spc700 {
         let reg_type = match opcode {
             0xD4 => 0,
             0xDB => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: addr, cycles: read_cycles } = self.read_from_pc();
@@ -760,7 +1189,7 @@ impl Spc700 {
         let data = match reg_type {
             0 => self.reg.a,
             2 => self.reg.y,    
-            _ => panic!("register type must be 0 or 2"),
+            _ => return self.halt_by_fault(opcode),
         };
         
         let OperationResult{ ret: _, cycles: write_cycles } = self.write_to_page(addr, data);
@@ -784,7 +1213,7 @@ impl Spc700 {
             0xC5 => 0,
             0xC9 => 1,
             0xCC => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_pc();
@@ -798,7 +1227,7 @@ impl Spc700 {
             0 => self.reg.a,
             1 => self.reg.x,
             2 => self.reg.y,    
-            _ => panic!("register type must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: _, cycles: write_cycles } = self.write_ram(addr, data);
@@ -894,7 +1323,7 @@ impl Spc700 {
             0x2D => 0,
             0x4D => 1,
             0x6D => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
         
         let data = match reg_type {
@@ -902,7 +1331,7 @@ impl Spc700 {
             1 => self.reg.x,
             2 => self.reg.y,
             3 => self.reg.psw.get(),
-            _ => panic!("register type must be between 0 to 3"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let write_cycles = self.write_to_stack(data).cycles();
@@ -917,7 +1346,7 @@ impl Spc700 {
             0xAE => 0,
             0xCE => 1,
             0xEE => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         self.reg.sp = self.reg.sp.wrapping_add(1);
@@ -928,7 +1357,7 @@ impl Spc700 {
             1 => self.reg.x = data,
             2 => self.reg.y = data,
             3 => self.reg.psw.set(data),
-            _ => panic!("register type must be between 0 to 3"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         OperationResult::new_unit(read_cycles + 2)
@@ -943,6 +1372,16 @@ impl Spc700 {
         OperationResult::new_unit(2)
     }
 
+    // Halts CPU instead of panicking when an instruction meets an unexpected state.
+    fn halt_by_fault(&mut self, opcode: u8) -> OperationResult<()> {
+        let pc = self.op_pc;
+        log::warn!("cpu halted by unexpected opcode {:#04x} at {:#06x}", opcode, pc);
+
+        self.fault = Some(Spc700Error::EmulationFault { pc, opcode });
+        self.is_stopped = true;
+        OperationResult::new_unit(2)
+    }
+
     fn clrp(&mut self, _opcode: u8) -> OperationResult<()> {
         self.reg.psw.negate_page();
         OperationResult::new_unit(1)
@@ -1139,7 +1578,7 @@ impl Spc700 {
         let require_x = match opcode {
             0x2E => false,
             0xDE => true,
-            _ => panic!("expected opcodes are 0x2E and 0xDE. actual: {:#04x}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
         let OperationResult{ ret: addr, cycles: addr_cycles } = 
             if require_x { 
@@ -1358,27 +1797,27 @@ impl Spc700 {
         OperationResult::new_unit(read_cycles + write_cycles + 2)
     }    
 
-    fn fetch_alu_op(opcode: u8) -> fn(&mut Register, u8, u8) -> u8 {
+    fn fetch_alu_op(opcode: u8) -> Option<AluOp> {
         let upper = (opcode >> 4) & 0x0F;
         match upper {
-            0x0 | 0x1 => or,
-            0x2 | 0x3 => and,
-            0x4 | 0x5 => eor,
-            0x6 | 0x7 => cmp,
-            0x8 | 0x9 => adc,
-            0xA | 0xB => sbc,
-            _ => panic!("upper must be between 0x0 to 0xB. actual {:#04x}", upper),
+            0x0 | 0x1 => Some(or),
+            0x2 | 0x3 => Some(and),
+            0x4 | 0x5 => Some(eor),
+            0x6 | 0x7 => Some(cmp),
+            0x8 | 0x9 => Some(adc),
+            0xA | 0xB => Some(sbc),
+            _ => None,
         } 
     }
 
-    fn fetch_shift_op(opcode: u8) -> fn(u8, bool) -> (u8, bool) {
+    fn fetch_shift_op(opcode: u8) -> Option<ShiftOp> {
         let upper = (opcode >> 4) & 0x0F;
         match upper {
-            0x0 | 0x1 => asl,
-            0x2 | 0x3 => rol,
-            0x4 | 0x5 => lsr,
-            0x6 | 0x7 => ror,
-            _ => panic!("upper expects to be between 0 to 7. actual: {:#04x}", upper),
+            0x0 | 0x1 => Some(asl),
+            0x2 | 0x3 => Some(rol),
+            0x4 | 0x5 => Some(lsr),
+            0x6 | 0x7 => Some(ror),
+            _ => None,
         }
     }
 
@@ -1386,7 +1825,7 @@ impl Spc700 {
         let op = match opcode {
             0x3E => cmp,
             0x7E => cmp,
-               _ => Spc700::fetch_alu_op(opcode),
+               _ => match Spc700::fetch_alu_op(opcode) { Some(op) => op, None => return self.halt_by_fault(opcode) },
         };
         let upper = (opcode >> 4) & 0x0F;
         let lower = opcode & 0x0F;
@@ -1394,7 +1833,7 @@ impl Spc700 {
             (upper, 0x4) if upper % 2 == 0 => 0,
             (0x3, 0xE) => 1,
             (0x7, 0xE) => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: addr, cycles: addr_cycles } = self.read_from_pc();        
@@ -1403,7 +1842,7 @@ impl Spc700 {
             0 => self.reg.a,
             1 => self.reg.x,
             2 => self.reg.y,
-            _ => panic!("from register types must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };         
 
         self.reg.a = op(&mut self.reg, a, b);        
@@ -1415,7 +1854,7 @@ impl Spc700 {
         let op = match opcode {
             0x1E => cmp,
             0x5E => cmp, 
-               _ => Spc700::fetch_alu_op(opcode),
+               _ => match Spc700::fetch_alu_op(opcode) { Some(op) => op, None => return self.halt_by_fault(opcode) },
         };
         let op_upper = (opcode >> 4) & 0x0F;
         let op_lower = opcode & 0x0F;
@@ -1423,7 +1862,7 @@ impl Spc700 {
             (upper, 0x5) if upper % 2 == 0 => 0,
             (0x1, 0xE) => 1,
             (0x5, 0xE) => 2,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
         
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_pc();
@@ -1437,7 +1876,7 @@ impl Spc700 {
             0 => self.reg.a,
             1 => self.reg.x,
             2 => self.reg.y,
-            _ => panic!("from register types must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };        
         
         self.reg.a = op(&mut self.reg, a, b);        
@@ -1446,7 +1885,7 @@ impl Spc700 {
     }
 
     fn alu_indirect_x(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let x = self.reg.x;
 
         let a = self.reg.a;
@@ -1459,7 +1898,7 @@ impl Spc700 {
     }
 
     fn alu_x_idx_indirect(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let OperationResult{ ret: addr, cycles: addr_cycles } = self.read_from_pc();
         let addr = addr.wrapping_add(self.reg.x);
         
@@ -1473,7 +1912,7 @@ impl Spc700 {
     }
 
     fn alu_x_idx_addr(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_pc();
         let OperationResult{ ret: upper, cycles: upper_cycles } = self.read_from_pc();
         let lower = lower as u16;
@@ -1491,7 +1930,7 @@ impl Spc700 {
     }
 
     fn alu_x_ind_ind(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let OperationResult{ ret: base_addr, cycles: base_addr_cycles } = self.read_from_pc();
         let base_addr = base_addr.wrapping_add(self.reg.x);        
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_page(base_addr);
@@ -1511,7 +1950,7 @@ impl Spc700 {
     }
 
     fn alu_y_ind_ind(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let OperationResult{ ret: base_addr, cycles: base_addr_cycles } = self.read_from_pc();
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_page(base_addr);
         let OperationResult{ ret: upper, cycles: upper_cycles } = self.read_from_page(base_addr.wrapping_add(1));
@@ -1531,7 +1970,7 @@ impl Spc700 {
     }
 
     fn alu_y_idx_addr(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_pc();
         let OperationResult{ ret: upper, cycles: upper_cycles } = self.read_from_pc();
         let lower = lower as u16;
@@ -1551,7 +1990,7 @@ impl Spc700 {
     }
 
     fn alu_x_y(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let op_upper = (opcode >> 4) & 0x0F;
         let is_cmp_op = op_upper == 0x6 || op_upper == 0x7;
 
@@ -1573,7 +2012,7 @@ impl Spc700 {
         let op = match opcode {
             0xC8 => cmp,
             0xAD => cmp,
-            _ => Spc700::fetch_alu_op(opcode)
+            _ => match Spc700::fetch_alu_op(opcode) { Some(op) => op, None => return self.halt_by_fault(opcode) },
         };
         let upper = (opcode >> 4) & 0x0F;
         let lower = opcode & 0x0F;
@@ -1581,14 +2020,14 @@ impl Spc700 {
             (upper, 0x8) if upper <= 0xB => 0,
             (0xC, 0x8) => 1,
             (0xA, 0xD) => 2, 
-            _ => panic!("unknown opcode combination: {:x}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let a = match reg_type {
             0 => self.reg.a,
             1 => self.reg.x,
             2 => self.reg.y,
-            _ => panic!("from register types must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: b, cycles: b_cycles } = self.read_from_pc();
@@ -1597,14 +2036,14 @@ impl Spc700 {
             0 => self.reg.a = ret,
             1 => self.reg.x = ret,
             2 => self.reg.y = ret,
-            _ => panic!("from register types must be between 0 to 2"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         OperationResult::new_unit(b_cycles)
     }
 
     fn alu_dp_imm(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let op_upper = (opcode >> 4) & 0x0F;
         let is_cmp_op = op_upper == 0x6 || op_upper == 0x7; 
 
@@ -1625,7 +2064,7 @@ impl Spc700 {
     }
 
     fn alu_dp_dp(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_alu_op(opcode);
+        let Some(op) = Spc700::fetch_alu_op(opcode) else { return self.halt_by_fault(opcode) };
         let op_upper = (opcode >> 4) & 0x0F;
         let is_cmp_op = op_upper == 0x6 || op_upper == 0x7; 
 
@@ -1647,7 +2086,7 @@ impl Spc700 {
     }
 
     fn shift_acc(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_shift_op(opcode);
+        let Some(op) = Spc700::fetch_shift_op(opcode) else { return self.halt_by_fault(opcode) };
 
         let ret = self.shift(opcode, self.reg.a, op);
         
@@ -1657,7 +2096,7 @@ impl Spc700 {
     }
 
     fn shift_dp(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_shift_op(opcode);
+        let Some(op) = Spc700::fetch_shift_op(opcode) else { return self.halt_by_fault(opcode) };
 
         let OperationResult{ ret: addr, cycles: addr_cycles } = self.read_from_pc();
         let OperationResult{ ret: data, cycles: data_cycles } = self.read_from_page(addr);        
@@ -1669,7 +2108,7 @@ impl Spc700 {
     }
 
     fn shift_x_idx_indirect(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_shift_op(opcode);
+        let Some(op) = Spc700::fetch_shift_op(opcode) else { return self.halt_by_fault(opcode) };
 
         let OperationResult{ ret: addr, cycles: addr_cycles } = self.read_from_pc();
         let addr = addr.wrapping_add(self.reg.x);
@@ -1683,7 +2122,7 @@ impl Spc700 {
     }
 
     fn shift_addr(&mut self, opcode: u8) -> OperationResult<()> {
-        let op = Spc700::fetch_shift_op(opcode);
+        let Some(op) = Spc700::fetch_shift_op(opcode) else { return self.halt_by_fault(opcode) };
 
         let OperationResult{ ret: lower, cycles: lower_cycles } = self.read_from_pc();
         let OperationResult{ ret: upper, cycles: upper_cycles } = self.read_from_pc();
@@ -1726,14 +2165,14 @@ impl Spc700 {
             (0xF, 0xC) => 2,
             (0x1, 0xD) => 1,
             (0x3, 0xD) => 1,
-            _ => panic!("unexpected opcode: {}", opcode),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let data = match reg_type {
             0 => &mut self.reg.a,
             1 => &mut self.reg.x,
             2 => &mut self.reg.y,
-            _ => panic!("expect 0 to 2 as register type"),
+            _ => return self.halt_by_fault(opcode),
         };
 
         let ret =
@@ -1849,7 +2288,7 @@ impl Spc700 {
         let operand = match upper {
             0x1 => 0xFFFF,
             0x3 => 0x0001,
-            _   => panic!("unexpected opcode: {}", opcode),
+            _   => return self.halt_by_fault(opcode),
         };
 
         let OperationResult{ ret: word_lower, cycles: lower_cycles } = self.read_from_page(addr);
@@ -2032,7 +2471,9 @@ impl Spc700 {
     }
 
     fn count_cycles(&mut self, cycle_count: u16) -> () {        
-        DSP::global().cycles(cycle_count);
+        let dsp_cycles = cycle_count as u32 * self.dsp_step + self.dsp_cycle_frac;
+        self.dsp_cycle_frac = dsp_cycles & 0xFFFF;
+        DSP::global().cycles((dsp_cycles >> 16) as u16);
         self.timer.iter_mut().for_each(|timer| timer.cycles(cycle_count));
         self.cycle_counter += cycle_count as u64;
         self.total_cycles += cycle_count as u64;
@@ -2128,4 +2569,88 @@ fn ror(operand: u8, is_carry: bool) -> (u8, bool) {
     let carried = (operand & 1) != 0;
 
     (ret, carried)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{lock_globals, spc_file, IDLE};
+
+    // voice 0 keyed on at the beginning, and echoed with 32ms delay and feedback
+    const ECHO_REGS: &[(u8, u8)] = &[
+        (0x00, 0x40), (0x01, 0x40), (0x03, 0x08), (0x05, 0x8F), (0x06, 0xE0),
+        (0x0C, 0x7F), (0x1C, 0x7F), (0x2C, 0x40), (0x3C, 0x40), (0x0D, 0x50), (0x0F, 0x7F),
+        (0x4D, 0x01), (0x6D, 0x60), (0x7D, 0x02), (0x6C, 0x00), (0x4C, 0x01),
+    ];
+
+    fn render_frames(emulator: &mut Spc700, frames: usize) -> Vec<i16> {
+        let mut buffer = vec![0; frames * 2];
+        emulator.render(&mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn seek_keeps_echo_buffer() {
+        let _lock = lock_globals();
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(IDLE, ECHO_REGS)).unwrap();
+
+        let skipped = 32000;
+        let played = render_frames(&mut emulator, skipped + 8000);
+        let expected = &played[skipped * 2..];
+        assert!(expected.iter().any(|&sample| sample != 0));
+
+        emulator.restart().unwrap();
+        emulator.seek(Duration::from_secs(1));
+        assert_eq!(render_frames(&mut emulator, 8000), expected);
+    }
+
+    #[test]
+    fn loudness_needs_a_gating_block() {
+        let _lock = lock_globals();
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(IDLE, ECHO_REGS)).unwrap();
+
+        // shorter than a gating block of 400ms
+        let result = emulator.analyze_loudness(Duration::from_millis(100));
+        assert!(matches!(result, Err(Spc700Error::InvalidState(_))));
+
+        let loudness = emulator.analyze_loudness(Duration::from_secs(1)).unwrap();
+        assert!(loudness.integrated_lufs.is_finite());
+    }
+
+    #[test]
+    fn events_are_stamped_by_instruction() {
+        let _lock = lock_globals();
+        // writes SRCN, pitch and KON of voice 0 through $F2/$F3 in 5 cycles each,
+        // and KOFF after a loop of 120 cycles
+        let program = [
+            0x8F, 0x04, 0xF2, 0x8F, 0x01, 0xF3,
+            0x8F, 0x02, 0xF2, 0x8F, 0x34, 0xF3,
+            0x8F, 0x03, 0xF2, 0x8F, 0x12, 0xF3,
+            0x8F, 0x4C, 0xF2, 0x8F, 0x01, 0xF3,
+            0xCD, 20, 0x1D, 0xD0, 0xFD,
+            0x8F, 0x5C, 0xF2, 0x8F, 0x01, 0xF3,
+            0x2F, 0xFE,
+        ];
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(&program, &[])).unwrap();
+        emulator.set_event_queue(true);
+        render_frames(&mut emulator, 8);
+
+        // a sample is generated every 64 cycles
+        let event = |cycle, sample, kind| DspEvent { cycle, sample, kind };
+        assert_eq!(emulator.drain_events(), [
+            event(5, 0, DspEventKind::RegisterWrite { addr: 0x04, data: 0x01 }),
+            event(5, 0, DspEventKind::SourceChange { voice: 0, srcn: 1 }),
+            event(15, 0, DspEventKind::RegisterWrite { addr: 0x02, data: 0x34 }),
+            event(15, 0, DspEventKind::PitchChange { voice: 0, pitch: 0x0034 }),
+            event(25, 0, DspEventKind::RegisterWrite { addr: 0x03, data: 0x12 }),
+            event(25, 0, DspEventKind::PitchChange { voice: 0, pitch: 0x1234 }),
+            event(35, 0, DspEventKind::RegisterWrite { addr: 0x4C, data: 0x01 }),
+            event(35, 0, DspEventKind::KeyOn { voice: 0 }),
+            event(165, 2, DspEventKind::RegisterWrite { addr: 0x5C, data: 0x01 }),
+            event(165, 2, DspEventKind::KeyOff { voice: 0 }),
+        ]);
+    }
+}
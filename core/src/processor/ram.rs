@@ -47,11 +47,12 @@ static mut RAM: Ram = Ram::new();
 
 pub struct Ram {
     pub ram: [u8; 0x10000],
+    rom: [u8; 64],
     pub read_log: Vec<(u16, u8)>,
     pub write_log: Vec<(u16, u8)>,
 
     ram_writable: bool,
-    rom_writable: bool,
+    rom_enable: bool,
 
     dsp_addr: u8,
 }
@@ -60,28 +61,35 @@ impl Ram {
     pub const fn new() -> Ram {
         Ram {
             ram: [0; 0x10000],
+            rom: BOOT_ROM_DATA,
             read_log: Vec::new(),
             write_log: Vec::new(),
 
             ram_writable: true,
-            rom_writable: false,
+            rom_enable: true,
 
             dsp_addr: 0,
         }        
     }
 
-    pub fn init(ram: &[u8; 0x10000], rom: &[u8; 64]) {
+    // `extra_ram` is the RAM hidden under the IPL ROM (0x101C0 in SPC files).
+    // While the ROM is enabled, the 64KB image holds the ROM at 0xFFC0,
+    // so the underlying RAM is taken from the extra RAM block instead.
+    pub fn init(ram: &[u8; 0x10000], extra_ram: &[u8; 64]) {
         let test = ram[0x00F0];
         let control = ram[0x00F1];
         let dsp_addr = ram[0x00F2];
         let ram_writable = (test & 2) > 0;
-        let rom_writable = (control & 0x80) == 0;
+        let rom_enable = (control & 0x80) > 0;
 
         let mut global = Self::global();
         global.ram.copy_from_slice(ram);
-        global.ram[0xFFC0..].copy_from_slice(&rom[..]);
+        if rom_enable {
+            global.ram[0xFFC0..].copy_from_slice(&extra_ram[..]);
+        }
+        global.rom = BOOT_ROM_DATA;
         global.ram_writable = ram_writable;
-        global.rom_writable = rom_writable;
+        global.rom_enable = rom_enable;
         global.dsp_addr = dsp_addr; 
     }
 
@@ -94,11 +102,14 @@ impl Ram {
         log::debug!("ram[r] addr: {:06x}", addr);
         if (0x00F0..=0x00FF).contains(&addr) {
             self.read_from_io(addr as usize, timer)
-        }  else {
+        } else if addr >= 0xFFC0 && self.rom_enable {
+            self.rom[(addr - 0xFFC0) as usize]
+        } else {
             self.ram[addr as usize]
         }
     }
 
+    // DSP always sees RAM, even if IPL ROM is mapped for CPU.
     #[inline]
     pub fn read_ram(&self, addr: u16) -> u8 {
         self.ram[addr as usize]
@@ -177,7 +188,7 @@ impl Ram {
             0x00F0..=0x00FF => self.write_to_io(addr as usize, data, timer),  // I/O Ports (writes are also passed to RAM)
             0x0100..=0x01FF => self.ram[addr as usize] = data,         // RAM (typically used for CPU stack)
             0x0200..=0xFFBF => self.ram[addr as usize] = data,         // RAM (code ,data, dir-table, brr-samples, echo-buffer, etc..)
-            0xFFC0..=0xFFFF => self.ram[addr as usize] = data,                
+            0xFFC0..=0xFFFF => self.ram[addr as usize] = data,         // RAM (writes always go to RAM, even if IPL ROM is enabled)
         };     
     }
 
@@ -194,7 +205,7 @@ impl Ram {
             0x00FB => timer[1].write_divider(data), // timer 1 divider settings
             0x00FC => timer[2].write_divider(data), // timer 2 divider settings
             0x00FD..=0x00FF => (), // writing to TxOUT is not available (T0OUT, T1OUT, T2OUT is read only).
-            _ => panic!("{:#06x} should not be io address", addr),
+            _ => log::warn!("{:#06x} should not be io address", addr),
         };
 
         // data is also written to ram
@@ -212,12 +223,12 @@ impl Ram {
         let timer1_enable = (data & 0x02) > 0;
         let timer2_enable = (data & 0x04) > 0;
 
-        let rom_writable = !((data & 0x80) > 0);
+        let rom_enable = (data & 0x80) > 0;
 
         if timer0_enable { timer[0].enable() } else { timer[0].disable() };
         if timer1_enable { timer[1].enable() } else { timer[1].disable() };
         if timer2_enable { timer[2].enable() } else { timer[2].disable() };
 
-        self.rom_writable = rom_writable;
+        self.rom_enable = rom_enable;
     }
 }
\ No newline at end of file
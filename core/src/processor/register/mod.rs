@@ -1,12 +1,10 @@
 mod flags;
 
-extern crate spc;
-
 use std::fmt;
 use std::fmt::Display;
 
 pub use self::flags::Flags;
-use spc::spc::Spc;
+use crate::spc_file::SpcFile;
 
 #[derive(Debug)]
 pub struct Register {
@@ -40,7 +38,7 @@ impl Register {
         }
     }
 
-    pub fn new_with_init(spc: &Spc) -> Register {
+    pub(crate) fn new_with_init(spc: &SpcFile) -> Register {
         Register {
             a: spc.a,
             x: spc.x,
@@ -1,67 +1,91 @@
 #[derive(Copy, Clone)]
+pub enum TimerClock {
+  Hz8000,
+  Hz64000,
+}
+
+// Each timer consists of three stages.
+//
+//   stage 1: prescaler that divides the clock into 8kHz (timer 0, 1) or 64kHz (timer 2) ticks.
+//            this stage keeps running even if the timer is disabled.
+//   stage 2: 8-bit up counter. When it reaches the divider ($FA-$FC), it is cleared and stage 3 is incremented.
+//   stage 3: 4-bit output counter ($FD-$FF). Reading it clears the counter.
+#[derive(Copy, Clone)]
 pub struct Timer {
   pub enable: bool,
-  pub cycle_counter: u16,
-  max_cycle: u16,
-  pub divided: u16,
-  // next_divider: u8,
-  pub divider: u16,
-  pub out: u8,
+  prescaler: u16,
+  prescaler_max: u16,
+  stage2: u8,
+  divider: u8,
+  next_divider: u8,
+  out: u8,
 }
 
 impl Timer {
-  pub fn new(hz: u32) -> Timer {
-    let max_cycle = match hz {
-      8000  => 256,
-      64000 => 32,
-      _ => panic!("{} is invalid, require 8000 or 64000", hz),
+  pub fn new(clock: TimerClock) -> Timer {
+    let prescaler_max = match clock {
+      TimerClock::Hz8000  => 256,
+      TimerClock::Hz64000 => 32,
     };
 
     Timer {
       enable: false,
-      cycle_counter: 0,
-      max_cycle: max_cycle,
-      divided: 0,
-      // next_divider: 0,
+      prescaler: 0,
+      prescaler_max,
+      stage2: 0,
       divider: 0,
+      next_divider: 0,
       out: 0,
     }
   }
 
-  pub fn new_with_init(hz: u32, divider: u8, out: u8) -> Timer {
-    let mut timer = Timer::new(hz);
-    timer.divider = if divider == 0 { 256 } else { divider as u16 };
-    timer.out = out % 16;
+  pub fn new_with_init(clock: TimerClock, divider: u8, out: u8) -> Timer {
+    let mut timer = Timer::new(clock);
+    timer.divider = divider;
+    timer.next_divider = divider;
+    timer.out = out & 0x0F;
 
     timer
   }
 
   pub fn cycles(&mut self, cycle: u16) -> () {
-    if self.enable {
-      self.cycle_counter += cycle;
-
-      if self.cycle_counter >= self.max_cycle {
-        self.cycle_counter -= self.max_cycle;
-        self.divided += 1;
-
-        if self.divided >= self.divider {
-          self.divided = 0;
-          self.out = (self.out + 1) % 16;
-        }        
-      }
+    self.prescaler += cycle;
+
+    while self.prescaler >= self.prescaler_max {
+      self.prescaler -= self.prescaler_max;
+      self.tick();
+    }
+  }
+
+  fn tick(&mut self) -> () {
+    // divider written via $FA-$FC is latched at stage 1 tick
+    self.divider = self.next_divider;
+
+    if !self.enable {
+      return;
+    }
+
+    // stage 2 is compared by equality, so 0 means 256 and
+    // lowering divider below current count makes stage 2 wrap around.
+    self.stage2 = self.stage2.wrapping_add(1);
+    if self.stage2 == self.divider {
+      self.stage2 = 0;
+      self.out = (self.out + 1) & 0x0F;
     }
   }
 
   pub fn enable(&mut self) -> () {
-    self.enable = true;    
-    self.divided = 0;  
-    self.cycle_counter = 0;  
-    // self.divider = self.next_divider;
+    // only the transition from disabled to enabled resets stage 2 and 3
+    if !self.enable {
+      self.stage2 = 0;
+      self.out = 0;
+    }
+
+    self.enable = true;
   }
 
   pub fn disable(&mut self) -> () {
-    self.enable = false;    
-    self.out = 0;    
+    self.enable = false;
   }
 
   pub fn read_out(&mut self) -> u8 {
@@ -70,7 +94,133 @@ impl Timer {
     out
   }
 
-  pub fn write_divider(&mut self, data: u8) -> () {    
-    self.divider = if data == 0 { 256 } else { data as u16 };    
+  pub fn write_divider(&mut self, data: u8) -> () {
+    self.next_divider = data;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TICK: u16 = 256;
+
+  fn enabled(divider: u8) -> Timer {
+    let mut timer = Timer::new_with_init(TimerClock::Hz8000, divider, 0);
+    timer.enable();
+    timer
+  }
+
+  #[test]
+  fn counts_up_at_divider() {
+    let mut timer = enabled(4);
+
+    timer.cycles(TICK * 3);
+    assert_eq!(timer.read_out(), 0);
+    timer.cycles(TICK);
+    assert_eq!(timer.read_out(), 1);
+  }
+
+  #[test]
+  fn timer2_uses_faster_prescaler() {
+    let mut timer = Timer::new_with_init(TimerClock::Hz64000, 1, 0);
+    timer.enable();
+
+    timer.cycles(32 * 5);
+    assert_eq!(timer.read_out(), 5);
+  }
+
+  #[test]
+  fn divider_zero_means_256() {
+    let mut timer = enabled(0);
+
+    timer.cycles(TICK * 255);
+    assert_eq!(timer.read_out(), 0);
+    timer.cycles(TICK);
+    assert_eq!(timer.read_out(), 1);
+  }
+
+  #[test]
+  fn output_is_4bit_and_cleared_by_read() {
+    let mut timer = enabled(1);
+
+    timer.cycles(TICK * 17);
+    assert_eq!(timer.read_out(), 1);
+    assert_eq!(timer.read_out(), 0);
+  }
+
+  #[test]
+  fn divider_write_takes_effect_at_next_tick() {
+    let mut timer = enabled(2);
+
+    timer.cycles(TICK - 1);
+    timer.write_divider(1);
+    assert_eq!(timer.divider, 2);
+
+    // the tick latches the new divider before comparing
+    timer.cycles(1);
+    assert_eq!(timer.divider, 1);
+    assert_eq!(timer.read_out(), 1);
+  }
+
+  #[test]
+  fn lowering_divider_below_count_wraps_stage2() {
+    let mut timer = enabled(10);
+
+    timer.cycles(TICK * 5);
+    timer.write_divider(3);
+
+    // stage 2 is 5 and must go around 256 before matching 3 again
+    timer.cycles(TICK * 253);
+    assert_eq!(timer.read_out(), 0);
+    timer.cycles(TICK);
+    assert_eq!(timer.read_out(), 1);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn enabling_resets_stage2_and_output() {
+    let mut timer = enabled(4);
+
+    timer.cycles(TICK * 6);
+    timer.disable();
+    timer.enable();
+
+    timer.cycles(TICK * 3);
+    assert_eq!(timer.read_out(), 0);
+    timer.cycles(TICK);
+    assert_eq!(timer.read_out(), 1);
+  }
+
+  #[test]
+  fn enabling_enabled_timer_keeps_counters() {
+    let mut timer = enabled(4);
+
+    timer.cycles(TICK * 6);
+    timer.enable();
+
+    timer.cycles(TICK * 2);
+    assert_eq!(timer.read_out(), 2);
+  }
+
+  #[test]
+  fn disabled_timer_holds_output() {
+    let mut timer = enabled(1);
+
+    timer.cycles(TICK * 3);
+    timer.disable();
+    timer.cycles(TICK * 3);
+    assert_eq!(timer.read_out(), 3);
+  }
+
+  #[test]
+  fn prescaler_runs_while_disabled() {
+    let mut timer = Timer::new_with_init(TimerClock::Hz8000, 1, 0);
+
+    timer.cycles(TICK - 16);
+    timer.enable();
+
+    // stage 1 was not reset by enabling, so the first tick comes early
+    timer.cycles(16);
+    assert_eq!(timer.read_out(), 1);
+  }
+}
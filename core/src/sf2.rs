@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+
+use crate::dsp::{rate_period, BrrSample, DspState};
+use crate::event::{DspEvent, DspEventKind};
+use crate::timing::SAMPLE_RATE;
+
+// zero points following each sample required by SoundFont
+const SAMPLE_PADDING: usize = 46;
+// points required before the loop start and after the loop end, so that synthesizers interpolate across the loop
+const LOOP_GUARD: usize = 8;
+// SoundFont requires loops of this length at least, while a loop of a single BRR block has 16 points
+const MIN_LOOP_LEN: usize = 32;
+// SoundFont requires samples of this length at least
+const MIN_SAMPLE_LEN: usize = 48;
+
+// generator operators
+const ATTACK_VOL_ENV: u16 = 34;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const INITIAL_ATTENUATION: u16 = 48;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+
+// exponential decay of DSP multiplies level by 255/256 at each step
+const EXP_STEPS_PER_100DB: f64 = 2941.0;
+// attenuation regarded as silence
+const SILENCE_CB: f64 = 1000.0;
+// release after KOFF decreases 8 per sample from the top
+const RELEASE_SECONDS: f64 = 0x800 as f64 / 8.0 / SAMPLE_RATE as f64;
+
+// pitch of looped waveform is estimated by autocorrelation of lags in this range
+const MIN_LAG: usize = 8;
+const MAX_LAG: usize = 2048;
+const ANALYSIS_LEN: usize = 4096;
+const MIN_CORRELATION: f64 = 0.5;
+// the shortest lag whose correlation is close to the best one is the fundamental period
+const PERIOD_THRESHOLD: f64 = 0.95;
+// root key for samples whose pitch is not estimated
+const DEFAULT_ROOT_KEY: u8 = 60;
+
+// Instrument as the sound driver uses it: a BRR sample with the envelope settings at key on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SpcInstrument {
+    pub srcn: u8,
+    pub adsr: u16,
+    pub gain: u8,
+}
+
+// Collects instruments keyed on during playback, and builds SoundFont 2 from them.
+pub struct Sf2Recorder {
+    instruments: Vec<SpcInstrument>,
+    samples: Vec<BrrSample>,
+    sample_idx: HashMap<u8, usize>,
+    // voice is keyed on in the current sample, and its settings are taken after registers are settled
+    key_on: [bool; 8],
+}
+
+impl Sf2Recorder {
+    pub fn new() -> Sf2Recorder {
+        Sf2Recorder {
+            instruments: Vec::new(),
+            samples: Vec::new(),
+            sample_idx: HashMap::new(),
+            key_on: [false; 8],
+        }
+    }
+
+    pub fn instruments(&self) -> &[SpcInstrument] {
+        &self.instruments
+    }
+
+    // `events` are emitted while `sample` is generated, and `state` is taken after that.
+    pub fn process(&mut self, _sample: u64, events: &[DspEvent], state: &DspState) {
+        for event in events {
+            if let DspEventKind::KeyOn { voice } = event.kind {
+                self.key_on[voice] = true;
+            }
+        }
+
+        for (idx, voice) in state.voices.iter().enumerate() {
+            if !std::mem::take(&mut self.key_on[idx]) {
+                continue;
+            }
+
+            let instrument = SpcInstrument { srcn: voice.srcn, adsr: voice.adsr, gain: voice.gain };
+            if !self.instruments.contains(&instrument) {
+                self.instruments.push(instrument);
+            }
+
+            let samples = &mut self.samples;
+            self.sample_idx.entry(voice.srcn).or_insert_with(|| {
+                samples.push(BrrSample::decode(state.sample_table_addr, voice.srcn));
+                samples.len() - 1
+            });
+        }
+    }
+
+    // Returns SoundFont named `name`, which has a preset for each instrument in the order of appearance.
+    pub fn finish(&self, name: &str) -> Vec<u8> {
+        let info = list(b"INFO", &[
+            chunk(b"ifil", &[2, 0, 1, 0]),
+            chunk(b"isng", &zstr("EMU8000")),
+            chunk(b"INAM", &zstr(if name.is_empty() { "SPC" } else { name })),
+        ]);
+
+        let mut smpl = Vec::new();
+        let mut shdr = Vec::new();
+        for sample in self.samples.iter() {
+            write_sample(&mut smpl, &mut shdr, sample);
+        }
+        write_name(&mut shdr, "EOS", 20);
+        shdr.resize(shdr.len() + 26, 0);
+        let sdta = list(b"sdta", &[chunk(b"smpl", &smpl)]);
+
+        let mut phdr = Vec::new();
+        let mut pbag = Vec::new();
+        let mut pgen = Vec::new();
+        let mut inst = Vec::new();
+        let mut ibag = Vec::new();
+        let mut igen = Vec::new();
+        for (idx, instrument) in self.instruments.iter().enumerate() {
+            let name = format!("{:02X} {:04X} {:02X}", instrument.srcn, instrument.adsr, instrument.gain);
+            write_name(&mut phdr, &name, 20);
+            phdr.extend_from_slice(&(idx as u16 % 128).to_le_bytes());
+            phdr.extend_from_slice(&(idx as u16 / 128).to_le_bytes());
+            phdr.extend_from_slice(&(idx as u16).to_le_bytes());
+            phdr.extend_from_slice(&[0; 12]);
+            write_bag(&mut pbag, pgen.len() / 4);
+            write_generator(&mut pgen, INSTRUMENT, idx as u16);
+
+            write_name(&mut inst, &name, 20);
+            inst.extend_from_slice(&(idx as u16).to_le_bytes());
+            write_bag(&mut ibag, igen.len() / 4);
+            let sample_idx = self.sample_idx[&instrument.srcn];
+            write_zone(&mut igen, instrument, &self.samples[sample_idx], sample_idx);
+        }
+
+        // terminal records
+        write_name(&mut phdr, "EOP", 20);
+        phdr.extend_from_slice(&[0, 0, 0, 0]);
+        phdr.extend_from_slice(&(self.instruments.len() as u16).to_le_bytes());
+        phdr.extend_from_slice(&[0; 12]);
+        write_bag(&mut pbag, pgen.len() / 4);
+        pgen.extend_from_slice(&[0; 4]);
+        write_name(&mut inst, "EOI", 20);
+        inst.extend_from_slice(&(self.instruments.len() as u16).to_le_bytes());
+        write_bag(&mut ibag, igen.len() / 4);
+        igen.extend_from_slice(&[0; 4]);
+
+        let pdta = list(b"pdta", &[
+            chunk(b"phdr", &phdr),
+            chunk(b"pbag", &pbag),
+            chunk(b"pmod", &[0; 10]),
+            chunk(b"pgen", &pgen),
+            chunk(b"inst", &inst),
+            chunk(b"ibag", &ibag),
+            chunk(b"imod", &[0; 10]),
+            chunk(b"igen", &igen),
+            chunk(b"shdr", &shdr),
+        ]);
+
+        let mut body = b"sfbk".to_vec();
+        body.extend_from_slice(&info);
+        body.extend_from_slice(&sdta);
+        body.extend_from_slice(&pdta);
+        chunk(b"RIFF", &body)
+    }
+}
+
+impl Default for Sf2Recorder {
+    fn default() -> Sf2Recorder {
+        Sf2Recorder::new()
+    }
+}
+
+// Volume envelope of SoundFont approximated from ADSR or GAIN.
+// Times are in seconds, and levels are attenuation in centibels.
+struct VolumeEnvelope {
+    attack: f64,
+    // time to decay by 100dB
+    decay: f64,
+    sustain: f64,
+    attenuation: f64,
+}
+
+impl VolumeEnvelope {
+    fn new(adsr: u16, gain: u8) -> VolumeEnvelope {
+        let seconds = |rate: usize, steps: f64| rate_period(rate).map(|period| period as f64 * steps / SAMPLE_RATE as f64);
+        let exp_decay = |rate: usize| seconds(rate, EXP_STEPS_PER_100DB);
+
+        if (adsr & 0x80) != 0 {
+            let attack_rate = (adsr & 0x0F) as usize * 2 + 1;
+            let decay_rate = ((adsr >> 4) & 0x07) as usize * 2 + 16;
+            let sustain_rate = ((adsr >> 8) & 0x1F) as usize;
+            let sustain_level = (((adsr >> 13) & 0x07) + 1) as f64 / 8.0;
+
+            // attack steps by 32 (1024 at the fastest rate) up to 0x7E0
+            let attack = if attack_rate == 31 { 0.0 } else { seconds(attack_rate, 63.0).unwrap_or(0.0) };
+            // SoundFont has no decay in sustain, so the sustain rate is approximated by decaying into silence
+            let (decay, sustain) = match exp_decay(sustain_rate) {
+                Some(decay) => (decay, SILENCE_CB),
+                None => (exp_decay(decay_rate).unwrap_or(0.0), to_centibels(sustain_level)),
+            };
+
+            return VolumeEnvelope { attack, decay, sustain, attenuation: 0.0 };
+        }
+
+        if (gain & 0x80) == 0 {
+            let level = (gain & 0x7F) as f64 * 16.0 / 0x7FF as f64;
+            return VolumeEnvelope { attack: 0.0, decay: 0.0, sustain: 0.0, attenuation: to_centibels(level) };
+        }
+
+        let rate = (gain & 0x1F) as usize;
+        match (gain >> 5) & 0x03 {
+            0 => VolumeEnvelope { attack: 0.0, decay: seconds(rate, 64.0).unwrap_or(0.0), sustain: SILENCE_CB, attenuation: 0.0 },
+            1 => VolumeEnvelope { attack: 0.0, decay: exp_decay(rate).unwrap_or(0.0), sustain: SILENCE_CB, attenuation: 0.0 },
+            2 => VolumeEnvelope { attack: seconds(rate, 64.0).unwrap_or(0.0), decay: 0.0, sustain: 0.0, attenuation: 0.0 },
+            // bent line steps by 32 up to 0x600, and by 8 above it
+            _ => VolumeEnvelope { attack: seconds(rate, 112.0).unwrap_or(0.0), decay: 0.0, sustain: 0.0, attenuation: 0.0 },
+        }
+    }
+}
+
+fn to_centibels(level: f64) -> f64 {
+    if level <= 0.0 { SILENCE_CB } else { (-200.0 * level.log10()).clamp(0.0, SILENCE_CB) }
+}
+
+fn to_timecents(seconds: f64) -> i16 {
+    if seconds <= 0.001 { -12000 } else { (1200.0 * seconds.log2()).round().clamp(-12000.0, 8000.0) as i16 }
+}
+
+// Estimates MIDI key played by the original pitch, as (root key, correction in cents).
+fn estimate_root_key(sample: &BrrSample) -> (u8, i8) {
+    let period = loop_start(sample).and_then(|start| estimate_period(&sample.data[start..]));
+    match period {
+        Some(period) => {
+            let key = 69.0 + 12.0 * (SAMPLE_RATE as f64 / period / 440.0).log2();
+            let root = key.round().clamp(0.0, 127.0);
+            (root as u8, ((root - key) * 100.0).round().clamp(-99.0, 99.0) as i8)
+        }
+        None => (DEFAULT_ROOT_KEY, 0),
+    }
+}
+
+// Fundamental period in samples of the looped waveform.
+fn estimate_period(waveform: &[i16]) -> Option<f64> {
+    if waveform.is_empty() {
+        return None;
+    }
+
+    let len = ANALYSIS_LEN.max(waveform.len() * 2);
+    let signal = waveform.iter().cycle().take(len).map(|&value| value as f64).collect::<Vec<_>>();
+    let max_lag = MAX_LAG.min(len / 2);
+    let correlation = |lag: usize| -> f64 {
+        let (head, tail) = (&signal[..len - lag], &signal[lag..]);
+        let product = head.iter().zip(tail).map(|(a, b)| a * b).sum::<f64>();
+        let energy = head.iter().map(|a| a * a).sum::<f64>() * tail.iter().map(|b| b * b).sum::<f64>();
+        if energy == 0.0 { 0.0 } else { product / energy.sqrt() }
+    };
+
+    let correlations = (MIN_LAG..=max_lag).map(correlation).collect::<Vec<_>>();
+    let best = correlations.iter().copied().fold(f64::MIN, f64::max);
+    if best < MIN_CORRELATION {
+        return None;
+    }
+
+    let idx = correlations.iter().position(|&value| value >= best * PERIOD_THRESHOLD)?;
+    // climb to the local peak, and refine it by parabolic interpolation
+    let idx = (idx..correlations.len()).take_while(|&idx| idx == 0 || correlations[idx] >= correlations[idx - 1]).last()?;
+    let offset = match (correlations.get(idx.wrapping_sub(1)), correlations.get(idx + 1)) {
+        (Some(&before), Some(&after)) => {
+            let curvature = before - 2.0 * correlations[idx] + after;
+            if curvature == 0.0 { 0.0 } else { 0.5 * (before - after) / curvature }
+        }
+        _ => 0.0,
+    };
+
+    Some((MIN_LAG + idx) as f64 + offset)
+}
+
+// Start of the loop, if the sample loops within its data.
+fn loop_start(sample: &BrrSample) -> Option<usize> {
+    sample.loop_start.filter(|&start| start < sample.data.len())
+}
+
+// Short loops are unrolled to `MIN_LOOP_LEN` points. A loop starting within `LOOP_GUARD` points is
+// preceded by its own tail if it starts at the beginning, or by silence otherwise.
+fn write_sample(smpl: &mut Vec<u8>, shdr: &mut Vec<u8>, sample: &BrrSample) {
+    let start = smpl.len() / 2;
+    let (data, loop_start, loop_end) = match loop_start(sample) {
+        Some(loop_start) => {
+            let waveform = &sample.data[loop_start..];
+            let lead = LOOP_GUARD.saturating_sub(loop_start);
+            let mut data = if loop_start == 0 {
+                let mut tail = waveform.iter().rev().cycle().take(lead).copied().collect::<Vec<_>>();
+                tail.reverse();
+                tail
+            } else {
+                vec![0; lead]
+            };
+
+            data.extend_from_slice(&sample.data);
+            let repeat = MIN_LOOP_LEN.div_ceil(waveform.len());
+            (1..repeat).for_each(|_| data.extend_from_slice(waveform));
+            let loop_end = data.len();
+            data.extend(waveform.iter().cycle().take(LOOP_GUARD));
+            (data, start + lead + loop_start, start + loop_end)
+        }
+        None => {
+            let mut data = sample.data.clone();
+            data.resize(data.len().max(MIN_SAMPLE_LEN), 0);
+            let end = start + data.len();
+            (data, start, end)
+        }
+    };
+    data.iter().for_each(|value| smpl.extend_from_slice(&value.to_le_bytes()));
+    smpl.resize(smpl.len() + SAMPLE_PADDING * 2, 0);
+
+    let (root_key, correction) = estimate_root_key(sample);
+    write_name(shdr, &format!("SRCN {:02X}", sample.srcn), 20);
+    [start, start + data.len(), loop_start, loop_end, SAMPLE_RATE as usize].iter()
+        .for_each(|&value| shdr.extend_from_slice(&(value as u32).to_le_bytes()));
+    shdr.push(root_key);
+    shdr.push(correction as u8);
+    // no link, mono sample
+    shdr.extend_from_slice(&0u16.to_le_bytes());
+    shdr.extend_from_slice(&1u16.to_le_bytes());
+}
+
+// Generators of an instrument zone. Sample ID must be the last one.
+fn write_zone(igen: &mut Vec<u8>, instrument: &SpcInstrument, sample: &BrrSample, sample_idx: usize) {
+    let envelope = VolumeEnvelope::new(instrument.adsr, instrument.gain);
+    write_generator(igen, ATTACK_VOL_ENV, to_timecents(envelope.attack) as u16);
+    write_generator(igen, DECAY_VOL_ENV, to_timecents(envelope.decay) as u16);
+    write_generator(igen, SUSTAIN_VOL_ENV, envelope.sustain.round() as u16);
+    write_generator(igen, RELEASE_VOL_ENV, to_timecents(RELEASE_SECONDS) as u16);
+    write_generator(igen, INITIAL_ATTENUATION, envelope.attenuation.round() as u16);
+    // loop continuously
+    write_generator(igen, SAMPLE_MODES, loop_start(sample).is_some() as u16);
+    write_generator(igen, SAMPLE_ID, sample_idx as u16);
+}
+
+fn write_generator(gen: &mut Vec<u8>, operator: u16, amount: u16) {
+    gen.extend_from_slice(&operator.to_le_bytes());
+    gen.extend_from_slice(&amount.to_le_bytes());
+}
+
+fn write_bag(bag: &mut Vec<u8>, generator_idx: usize) {
+    bag.extend_from_slice(&(generator_idx as u16).to_le_bytes());
+    // modulators are not used
+    bag.extend_from_slice(&0u16.to_le_bytes());
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str, len: usize) {
+    let mut field = name.bytes().filter(u8::is_ascii).take(len - 1).collect::<Vec<_>>();
+    field.resize(len, 0);
+    bytes.extend_from_slice(&field);
+}
+
+// zero terminated string padded to even length
+fn zstr(text: &str) -> Vec<u8> {
+    let mut bytes = text.bytes().filter(u8::is_ascii).collect::<Vec<_>>();
+    bytes.push(0);
+    if !bytes.len().is_multiple_of(2) {
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = id.to_vec();
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    if !data.len().is_multiple_of(2) {
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = kind.to_vec();
+    chunks.iter().for_each(|sub| data.extend_from_slice(sub));
+    chunk(b"LIST", &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sub-chunks of a RIFF chunk body as (ID, data).
+    fn chunks(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            chunks.push((&data[..4], &data[8..8 + len]));
+            data = &data[(8 + len).next_multiple_of(2)..];
+        }
+        chunks
+    }
+
+    fn read_u32(bytes: &[u8], pos: usize) -> usize {
+        u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    fn square(period: usize, len: usize) -> Vec<i16> {
+        (0..len).map(|idx| if idx % period < period / 2 { 8000 } else { -8000 }).collect()
+    }
+
+    fn brr_sample(srcn: u8, data: Vec<i16>, loop_start: Option<usize>) -> BrrSample {
+        BrrSample { srcn, start_addr: 0x1000, loop_addr: 0x1000, data, loop_start }
+    }
+
+    #[test]
+    fn period_of_looped_waveform() {
+        let sine = (0..200).map(|idx| (8000.0 * (idx as f64 * 2.0 * std::f64::consts::PI / 50.0).sin()) as i16).collect::<Vec<_>>();
+        assert!((estimate_period(&sine).unwrap() - 50.0).abs() < 0.1);
+        // a single BRR block
+        assert!((estimate_period(&square(16, 16)).unwrap() - 16.0).abs() < 0.1);
+        // harmonics do not halve the period
+        let rich = (0..256).map(|idx| {
+            let phase = idx as f64 * 2.0 * std::f64::consts::PI / 64.0;
+            (4000.0 * (phase.sin() + 0.8 * (2.0 * phase).sin() + 0.6 * (3.0 * phase).sin())) as i16
+        }).collect::<Vec<_>>();
+        assert!((estimate_period(&rich).unwrap() - 64.0).abs() < 0.1);
+
+        assert_eq!(estimate_period(&[]), None);
+        assert_eq!(estimate_period(&[0; 64]), None);
+    }
+
+    #[test]
+    fn envelope_from_adsr_and_gain() {
+        // fastest attack, decay rate 16 into sustain level 2/8 without sustain decay
+        let envelope = VolumeEnvelope::new(0x208F, 0);
+        assert_eq!(envelope.attack, 0.0);
+        assert!((envelope.decay - 64.0 * EXP_STEPS_PER_100DB / 32000.0).abs() < 1e-9);
+        assert!((envelope.sustain - 200.0 * 4f64.log10()).abs() < 1e-9);
+        assert_eq!(envelope.attenuation, 0.0);
+
+        // attack rate 1 takes 63 steps of 2048 samples, and sustain rate 31 decays into silence
+        let envelope = VolumeEnvelope::new(0x1F80, 0);
+        assert!((envelope.attack - 2048.0 * 63.0 / 32000.0).abs() < 1e-9);
+        assert!((envelope.decay - EXP_STEPS_PER_100DB / 32000.0).abs() < 1e-9);
+        assert_eq!(envelope.sustain, SILENCE_CB);
+
+        // direct GAIN of half level
+        let envelope = VolumeEnvelope::new(0, 0x40);
+        assert!((envelope.attenuation - to_centibels(0x400 as f64 / 0x7FF as f64)).abs() < 1e-9);
+        assert_eq!((envelope.attack, envelope.decay), (0.0, 0.0));
+
+        // linear decrease at rate 31 reaches 0 in 64 samples
+        let envelope = VolumeEnvelope::new(0, 0x9F);
+        assert!((envelope.decay - 64.0 / 32000.0).abs() < 1e-9);
+        assert_eq!(envelope.sustain, SILENCE_CB);
+        // GAIN is infinite at rate 0
+        assert_eq!(VolumeEnvelope::new(0, 0xC0).attack, 0.0);
+    }
+
+    #[test]
+    fn chunk_layout() {
+        let mut recorder = Sf2Recorder::new();
+        // a single block loop, a short sample without loop, a loop starting past the data,
+        // and a loop starting right after the first points
+        let samples = [
+            brr_sample(0, square(16, 16), Some(0)),
+            brr_sample(1, square(16, 32), None),
+            brr_sample(2, square(16, 32), Some(32)),
+            brr_sample(3, square(16, 64), Some(2)),
+        ];
+        for (idx, sample) in samples.into_iter().enumerate() {
+            recorder.instruments.push(SpcInstrument { srcn: sample.srcn, adsr: 0xE08F, gain: 0 });
+            recorder.sample_idx.insert(sample.srcn, idx);
+            recorder.samples.push(sample);
+        }
+        let bytes = recorder.finish("Game");
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4), bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"sfbk");
+        let lists = chunks(&bytes[12..]);
+        let kinds = lists.iter().map(|(id, data)| (*id, &data[..4])).collect::<Vec<_>>();
+        assert_eq!(kinds, [(&b"LIST"[..], &b"INFO"[..]), (b"LIST", b"sdta"), (b"LIST", b"pdta")]);
+
+        let info = chunks(&lists[0].1[4..]);
+        assert_eq!(info[0], (&b"ifil"[..], &[2, 0, 1, 0][..]));
+        assert_eq!(info[2], (&b"INAM"[..], &b"Game\0\0"[..]));
+
+        let smpl = chunks(&lists[1].1[4..])[0].1;
+        let pdta = chunks(&lists[2].1[4..]);
+        let ids = pdta.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, [&b"phdr"[..], b"pbag", b"pmod", b"pgen", b"inst", b"ibag", b"imod", b"igen", b"shdr"]);
+        // records of each instrument and a terminal record
+        let record_sizes = [38, 4, 10, 4, 22, 4, 10, 4, 46];
+        let records = pdta.iter().zip(record_sizes).map(|((_, data), size)| data.len() / size).collect::<Vec<_>>();
+        assert_eq!(records, [5, 5, 1, 5, 5, 5, 1, 4 * 7 + 1, 5]);
+
+        // a single block loop is preceded by its tail, unrolled to 32 points, and followed by its head
+        let shdr = pdta[8].1;
+        let points = |idx: usize| (0..4).map(|field| read_u32(shdr, idx * 46 + 20 + field * 4)).collect::<Vec<_>>();
+        assert_eq!(points(0), [0, 48, LOOP_GUARD, LOOP_GUARD + 32]);
+        let value = |idx: usize| i16::from_le_bytes([smpl[idx * 2], smpl[idx * 2 + 1]]);
+        assert!((0..48).all(|idx| value(idx) == value(LOOP_GUARD + (idx + 16 - LOOP_GUARD) % 16)));
+        // samples without loop are padded to 48 points
+        let second = 48 + SAMPLE_PADDING;
+        assert_eq!(points(1), [second, second + 48, second, second + 48]);
+        assert!((second + 32..second + 48).all(|idx| value(idx) == 0));
+        let third = second + 48 + SAMPLE_PADDING;
+        assert_eq!(points(2), [third, third + 48, third, third + 48]);
+        // a loop starting within the guard is preceded by silence
+        let fourth = third + 48 + SAMPLE_PADDING;
+        let lead = LOOP_GUARD - 2;
+        assert_eq!(points(3), [fourth, fourth + lead + 64 + LOOP_GUARD, fourth + LOOP_GUARD, fourth + lead + 64]);
+        assert!((fourth..fourth + lead).all(|idx| value(idx) == 0));
+        assert_eq!(smpl.len(), (fourth + lead + 64 + LOOP_GUARD + SAMPLE_PADDING) * 2);
+
+        // looped samples have 8 points before the loop, a loop of 32 points at least, and 8 points after it
+        for idx in [0, 3] {
+            let [start, end, loop_start, loop_end] = points(idx)[..] else { unreachable!() };
+            assert!(loop_start - start >= 8 && loop_end - loop_start >= 32 && end - loop_end >= 8);
+        }
+
+        // sample modes is set only if the loop is written
+        let igen = pdta[7].1;
+        let modes = igen.chunks(4)
+            .filter(|generator| u16::from_le_bytes([generator[0], generator[1]]) == SAMPLE_MODES)
+            .map(|generator| u16::from_le_bytes([generator[2], generator[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(modes, [1, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::dsp::DSP;
+use crate::processor::ram::Ram;
+use crate::timing::{duration_to_samples, samples_to_duration};
+
+// key on timing may jitter by timer phase against DSP sample timing
+const TIMING_TOLERANCE: u64 = 2;
+const MAX_CANDIDATES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SongEnd {
+    // All voices are released and output keeps silent from `at`.
+    Silence { at: Duration },
+    // Song repeats `length` from `intro`.
+    Loop { intro: Duration, length: Duration },
+}
+
+impl SongEnd {
+    // Position to start fading out after playing the loop `loops` times.
+    pub fn end_position(&self, loops: u32) -> Duration {
+        match *self {
+            SongEnd::Silence { at } => at,
+            SongEnd::Loop { intro, length } => intro + length * loops,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SongEndOptions {
+    // absolute output level regarded as silence
+    pub silence_threshold: u16,
+    // song is ended if silence continues this duration
+    pub silence_duration: Duration,
+    // RAM regions hashed at each key on to identify the state of the sound driver
+    pub hash_regions: Vec<Range<u16>>,
+    // repetitions shorter than this are not regarded as the song loop
+    pub min_loop_length: Duration,
+    // detection gives up at this position
+    pub max_duration: Duration,
+}
+
+impl Default for SongEndOptions {
+    fn default() -> SongEndOptions {
+        SongEndOptions {
+            silence_threshold: 16,
+            silence_duration: Duration::from_secs(3),
+            // most drivers keep sequencer state in direct page
+            hash_regions: vec![Range { start: 0x0000, end: 0x00F0 }],
+            min_loop_length: Duration::from_secs(1),
+            max_duration: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct KeyOnEvent {
+    sample: u64,
+    kon: u8,
+    hash: u64,
+}
+
+// Loop candidate starting at `start`th event.
+// It is confirmed when the events of one period repeat with the same timing.
+struct Candidate {
+    start: usize,
+    period_events: usize,
+    period_samples: u64,
+}
+
+// Detects song end from key on events and output samples.
+// A loop is found when the key on events, together with the driver state hashed at each key on,
+// repeat a whole period. Intro length is measured at the first key on of the loop.
+pub(crate) struct SongEndDetector {
+    options: SongEndOptions,
+    events: Vec<KeyOnEvent>,
+    occurrences: HashMap<(u64, u8), Vec<usize>>,
+    candidates: Vec<Candidate>,
+    silence_start: Option<u64>,
+}
+
+impl SongEndDetector {
+    pub fn new(options: SongEndOptions) -> SongEndDetector {
+        SongEndDetector {
+            options,
+            events: Vec::new(),
+            occurrences: HashMap::new(),
+            candidates: Vec::new(),
+            silence_start: None,
+        }
+    }
+
+    // Called right after the instruction writing KON register,
+    // so that the driver state is hashed at the same point of its code every time.
+    pub fn key_on(&mut self, sample: u64, kon: u8) -> Option<SongEnd> {
+        let hash = hash_regions(&self.options.hash_regions);
+        self.push_event(KeyOnEvent { sample, kon, hash })
+    }
+
+    // Called after DSP generates the `sample`th sample.
+    pub fn update(&mut self, sample: u64, left: i16, right: i16) -> Option<SongEnd> {
+        let dsp = DSP::global();
+
+        // silence at the beginning of the song is not the end
+        let threshold = self.options.silence_threshold;
+        let is_silent = !self.events.is_empty()
+            && dsp.is_all_voices_released()
+            && left.unsigned_abs() <= threshold
+            && right.unsigned_abs() <= threshold;
+
+        self.silence_start = if is_silent { self.silence_start.or(Some(sample)) } else { None };
+        match self.silence_start {
+            Some(start) if sample - start >= duration_to_samples(self.options.silence_duration) => {
+                Some(SongEnd::Silence { at: samples_to_duration(start) })
+            }
+            _ => None,
+        }
+    }
+
+    fn push_event(&mut self, event: KeyOnEvent) -> Option<SongEnd> {
+        let idx = self.events.len();
+        self.events.push(event);
+
+        let events = &self.events;
+        self.candidates.retain(|candidate| {
+            let prev = &events[idx - candidate.period_events];
+            prev.kon == event.kon
+                && prev.hash == event.hash
+                && (event.sample - prev.sample).abs_diff(candidate.period_samples) <= TIMING_TOLERANCE
+        });
+
+        let confirmed = self.candidates.iter()
+            .find(|candidate| idx + 1 >= candidate.start + candidate.period_events * 2)
+            .map(|candidate| SongEnd::Loop {
+                intro: samples_to_duration(events[candidate.start].sample),
+                length: samples_to_duration(candidate.period_samples),
+            });
+        if confirmed.is_some() {
+            return confirmed;
+        }
+
+        let min_loop_length = duration_to_samples(self.options.min_loop_length);
+        let occurrences = self.occurrences.entry((event.hash, event.kon)).or_default();
+        for &start in occurrences.iter() {
+            let period_samples = event.sample - events[start].sample;
+            if period_samples >= min_loop_length && self.candidates.len() < MAX_CANDIDATES {
+                self.candidates.push(Candidate { start, period_events: idx - start, period_samples });
+            }
+        }
+        occurrences.push(idx);
+
+        None
+    }
+}
+
+fn hash_regions(regions: &[Range<u16>]) -> u64 {
+    let ram = &Ram::global().ram;
+    let mut hasher = DefaultHasher::new();
+    regions.iter().for_each(|region| ram[region.start as usize..region.end as usize].hash(&mut hasher));
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Spc700;
+    use crate::testing::{lock_globals, spc_file};
+
+    // Timer 0 counts up every 200 ticks of 8kHz, that is every 800 samples.
+    const TIMER_PERIOD: u64 = 800;
+    const START_TIMER: &[u8] = &[0x8F, 200, 0xFA, 0x8F, 0x01, 0xF1];
+    // Polls the counter of timer 0 until it counts up.
+    const WAIT_TIMER: &[u8] = &[0xE4, 0xFD, 0xF0, 0xFC];
+    // voice 0 at full volume and fast attack, sustained at full level until key off
+    const VOICE_REGS: &[(u8, u8)] = &[
+        (0x00, 0x7F), (0x01, 0x7F), (0x03, 0x10), (0x05, 0x8F), (0x06, 0xE0),
+        (0x0C, 0x7F), (0x1C, 0x7F), (0x6C, 0x20),
+    ];
+
+    fn write_dsp(addr: u8, data: u8) -> [u8; 6] {
+        [0x8F, addr, 0xF2, 0x8F, data, 0xF3]
+    }
+
+    fn detect(program: &[u8]) -> Option<SongEnd> {
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(program, VOICE_REGS)).unwrap();
+        let options = SongEndOptions {
+            silence_duration: Duration::from_millis(100),
+            min_loop_length: Duration::from_millis(10),
+            max_duration: Duration::from_secs(2),
+            ..SongEndOptions::default()
+        };
+        emulator.detect_song_end(&options).unwrap()
+    }
+
+    #[test]
+    fn detects_loop_of_key_ons() {
+        let _lock = lock_globals();
+        // keys on voice 1 once as the intro, then voice 0 every timer period
+        let program = [START_TIMER, &write_dsp(0x4C, 0x02), WAIT_TIMER, &write_dsp(0x4C, 0x01), WAIT_TIMER, &[0x2F, 0xF4]].concat();
+
+        let Some(SongEnd::Loop { intro, length }) = detect(&program) else { panic!("loop is not detected") };
+        // the loop starts at the first key on of voice 0
+        assert!(duration_to_samples(intro).abs_diff(TIMER_PERIOD) <= TIMING_TOLERANCE);
+        assert!(duration_to_samples(length).abs_diff(TIMER_PERIOD) <= TIMING_TOLERANCE);
+    }
+
+    #[test]
+    fn detects_silence_after_release() {
+        let _lock = lock_globals();
+        // keys voice 0 off one timer period after keying it on
+        let program = [START_TIMER, &write_dsp(0x4C, 0x01), WAIT_TIMER, &write_dsp(0x5C, 0x01), &[0x2F, 0xFE]].concat();
+
+        let Some(SongEnd::Silence { at }) = detect(&program) else { panic!("silence is not detected") };
+        // release lowers the envelope from 0x7FF by 8 every sample
+        assert!(duration_to_samples(at).abs_diff(TIMER_PERIOD + 0x800 / 8) <= TIMING_TOLERANCE);
+    }
+}
@@ -0,0 +1,375 @@
+// SPC2 is a container packing several SPC snapshots into one file.
+// RAM is split into 256 bytes blocks, and identical blocks are shared among songs.
+// Only major version 1 is accepted, and files are written as version 1.0.
+// Metadata offsets below could not be checked against the specification or a real file yet,
+// so dumper, dump date, length and fade are neither read nor written; songs fall back to song end detection.
+//
+// layout:
+//   header (16 bytes)
+//     0x00  "KSPC" 0x1A
+//     0x05  major version (1)
+//     0x06  minor version (0)
+//     0x07  number of songs (u16)
+//     0x09  reserved (7 bytes)
+//   song records (1024 bytes each)
+//     0x000 DSP registers (128 bytes)
+//     0x080 extra RAM under IPL ROM (64 bytes)
+//     0x0C0 RAM block indices for each page (256 * u16)
+//     0x2C0 PC (u16), A, X, Y, PSW, SP
+//     0x2C7 reserved
+//     0x2D0 channel disables, emulator
+//     0x2D2 reserved
+//     0x2E0 song title (32), game title (32), artist (32)
+//     0x340 reserved
+//     0x360 comments (32)
+//     0x380 reserved
+//   RAM blocks (256 bytes each)
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use crate::error::{Result, Spc700Error};
+use crate::spc_file::{SpcFile, Id666};
+
+const SIGNATURE: &[u8] = b"KSPC\x1A";
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 0;
+const HEADER_SIZE: usize = 16;
+const SONG_SIZE: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+
+// text fields in song record, (offset, length)
+const SONG_TITLE: (usize, usize) = (0x2E0, 32);
+const GAME_TITLE: (usize, usize) = (0x300, 32);
+const ARTIST: (usize, usize) = (0x320, 32);
+const COMMENTS: (usize, usize) = (0x360, 32);
+
+pub struct Spc2Song {
+    pub tag: Id666,
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    psw: u8,
+    sp: u8,
+    regs: [u8; 128],
+    extra_ram: [u8; 64],
+    blocks: [u16; 256],
+}
+
+pub struct Spc2File {
+    songs: Vec<Spc2Song>,
+    blocks: Vec<[u8; BLOCK_SIZE]>,
+}
+
+impl Spc2File {
+    pub fn open(p: &path::Path) -> Result<Spc2File> {
+        let bytes = fs::read(p)?;
+        Spc2File::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Spc2File> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(SIGNATURE) {
+            return Err(Spc700Error::BadHeader("signature is not found".to_string()));
+        }
+
+        if bytes[5] != MAJOR_VERSION {
+            return Err(Spc700Error::UnsupportedVersion(format!("{}.{}", bytes[5], bytes[6])));
+        }
+
+        let song_count = u16_at(bytes, 7) as usize;
+        let blocks_offset = HEADER_SIZE + song_count * SONG_SIZE;
+        if bytes.len() < blocks_offset {
+            return Err(Spc700Error::TruncatedFile { expected: blocks_offset, actual: bytes.len() });
+        }
+
+        let blocks = bytes[blocks_offset..].chunks_exact(BLOCK_SIZE)
+            .map(|chunk| {
+                let mut block = [0; BLOCK_SIZE];
+                block.copy_from_slice(chunk);
+                block
+            })
+            .collect::<Vec<_>>();
+
+        let songs = bytes[HEADER_SIZE..blocks_offset].chunks_exact(SONG_SIZE)
+            .map(Spc2Song::parse)
+            .collect::<Vec<_>>();
+
+        // every referred block must exist
+        let required = songs.iter()
+            .flat_map(|song| song.blocks.iter())
+            .map(|&idx| idx as usize + 1)
+            .max()
+            .unwrap_or(0);
+        if required > blocks.len() {
+            let expected = blocks_offset + required * BLOCK_SIZE;
+            return Err(Spc700Error::TruncatedFile { expected, actual: bytes.len() });
+        }
+
+        Ok(Spc2File { songs, blocks })
+    }
+
+    // Builds SPC2 from SPC files. RAM blocks are deduplicated among all songs.
+    pub fn from_spcs<'a>(spcs: impl IntoIterator<Item = &'a [u8]>) -> Result<Spc2File> {
+        let mut block_table: HashMap<[u8; BLOCK_SIZE], u16> = HashMap::new();
+        let mut blocks = Vec::new();
+        let mut songs = Vec::new();
+
+        for bytes in spcs {
+            let spc = SpcFile::parse(bytes)?;
+            let mut indices = [0; 256];
+            for (page, idx) in spc.ram.chunks_exact(BLOCK_SIZE).zip(indices.iter_mut()) {
+                let mut block = [0; BLOCK_SIZE];
+                block.copy_from_slice(page);
+
+                *idx = match block_table.get(&block) {
+                    Some(&idx) => idx,
+                    None => {
+                        if blocks.len() > u16::MAX as usize {
+                            return Err(Spc700Error::InvalidState("too many RAM blocks for SPC2".to_string()));
+                        }
+
+                        let idx = blocks.len() as u16;
+                        block_table.insert(block, idx);
+                        blocks.push(block);
+                        idx
+                    }
+                };
+            }
+
+            songs.push(Spc2Song {
+                tag: spc.tag.clone().unwrap_or_default(),
+                pc: spc.pc,
+                a: spc.a,
+                x: spc.x,
+                y: spc.y,
+                psw: spc.psw,
+                sp: spc.sp,
+                regs: spc.regs,
+                extra_ram: spc.extra_ram,
+                blocks: indices,
+            });
+        }
+
+        if songs.len() > u16::MAX as usize {
+            return Err(Spc700Error::InvalidState("too many songs for SPC2".to_string()));
+        }
+
+        Ok(Spc2File { songs, blocks })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.songs.len() * SONG_SIZE + self.blocks.len() * BLOCK_SIZE);
+        bytes.extend_from_slice(SIGNATURE);
+        bytes.push(MAJOR_VERSION);
+        bytes.push(MINOR_VERSION);
+        bytes.extend_from_slice(&(self.songs.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&[0; 7]);
+
+        self.songs.iter().for_each(|song| bytes.extend_from_slice(&song.to_bytes()));
+        self.blocks.iter().for_each(|block| bytes.extend_from_slice(block));
+
+        bytes
+    }
+
+    pub fn save(&self, p: &path::Path) -> Result<()> {
+        fs::write(p, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn songs(&self) -> &[Spc2Song] {
+        &self.songs
+    }
+
+    pub(crate) fn spc_file(&self, idx: usize) -> Result<SpcFile> {
+        let song = self.songs.get(idx)
+            .ok_or_else(|| Spc700Error::InvalidState(format!("song {} is not found in SPC2", idx)))?;
+
+        let mut ram = Box::new([0; 0x10000]);
+        ram.chunks_exact_mut(BLOCK_SIZE).zip(song.blocks.iter()).for_each(|(page, &idx)| {
+            page.copy_from_slice(&self.blocks[idx as usize]);
+        });
+
+        Ok(SpcFile {
+            pc: song.pc,
+            a: song.a,
+            x: song.x,
+            y: song.y,
+            psw: song.psw,
+            sp: song.sp,
+            ram,
+            regs: song.regs,
+            extra_ram: song.extra_ram,
+            tag: Some(song.tag.clone()),
+        })
+    }
+}
+
+impl Spc2Song {
+    fn parse(record: &[u8]) -> Spc2Song {
+        let mut regs = [0; 128];
+        let mut extra_ram = [0; 64];
+        let mut blocks = [0; 256];
+        regs.copy_from_slice(&record[0x000..0x080]);
+        extra_ram.copy_from_slice(&record[0x080..0x0C0]);
+        blocks.iter_mut().enumerate().for_each(|(page, idx)| *idx = u16_at(record, 0x0C0 + page * 2));
+
+        let tag = Id666 {
+            song_title: read_text(record, SONG_TITLE),
+            game_title: read_text(record, GAME_TITLE),
+            artist: read_text(record, ARTIST),
+            comments: read_text(record, COMMENTS),
+            channel_disables: record[0x2D0],
+            emulator: record[0x2D1],
+            ..Id666::default()
+        };
+
+        Spc2Song {
+            tag,
+            pc: u16_at(record, 0x2C0),
+            a: record[0x2C2],
+            x: record[0x2C3],
+            y: record[0x2C4],
+            psw: record[0x2C5],
+            sp: record[0x2C6],
+            regs,
+            extra_ram,
+            blocks,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; SONG_SIZE] {
+        let mut record = [0; SONG_SIZE];
+        record[0x000..0x080].copy_from_slice(&self.regs);
+        record[0x080..0x0C0].copy_from_slice(&self.extra_ram);
+        self.blocks.iter().enumerate().for_each(|(page, idx)| {
+            record[0x0C0 + page * 2..0x0C2 + page * 2].copy_from_slice(&idx.to_le_bytes());
+        });
+
+        record[0x2C0..0x2C2].copy_from_slice(&self.pc.to_le_bytes());
+        record[0x2C2] = self.a;
+        record[0x2C3] = self.x;
+        record[0x2C4] = self.y;
+        record[0x2C5] = self.psw;
+        record[0x2C6] = self.sp;
+        record[0x2D0] = self.tag.channel_disables;
+        record[0x2D1] = self.tag.emulator;
+
+        write_text(&mut record, SONG_TITLE, &self.tag.song_title);
+        write_text(&mut record, GAME_TITLE, &self.tag.game_title);
+        write_text(&mut record, ARTIST, &self.tag.artist);
+        write_text(&mut record, COMMENTS, &self.tag.comments);
+
+        record
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_text(record: &[u8], (offset, len): (usize, usize)) -> String {
+    let field = &record[offset..offset + len];
+    let field = field.split(|&b| b == 0).next().unwrap_or(field);
+    String::from_utf8_lossy(field).trim().to_string()
+}
+
+// Text longer than the field is truncated at a character boundary.
+fn write_text(record: &mut [u8], (offset, len): (usize, usize), text: &str) {
+    let mut end = text.len().min(len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    record[offset..offset + end].copy_from_slice(&text.as_bytes()[..end]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spc_file::SPC_FILE_MIN_LEN;
+
+    // SPC file with binary ID666 tag, whose RAM has a page filled by `fill` and a page shared with other songs
+    fn spc_bytes(pc: u16, fill: u8, title: &str, length_secs: u32) -> Vec<u8> {
+        let mut bytes = vec![0; SPC_FILE_MIN_LEN];
+        bytes[..33].copy_from_slice(b"SNES-SPC700 Sound File Data v0.30");
+        bytes[0x21] = 0x1A;
+        bytes[0x22] = 0x1A;
+        bytes[0x23] = 0x1A;
+        bytes[0x25..0x27].copy_from_slice(&pc.to_le_bytes());
+        bytes[0x27..0x2C].copy_from_slice(&[1, 2, 3, 0x02, 0xEF]);
+        bytes[0x2E..0x2E + title.len()].copy_from_slice(title.as_bytes());
+        bytes[0x4E..0x52].copy_from_slice(b"Game");
+        bytes[0xA9..0xAC].copy_from_slice(&length_secs.to_le_bytes()[..3]);
+        bytes[0xAC..0xB0].copy_from_slice(&10000u32.to_le_bytes());
+        bytes[0xB0..0xB8].copy_from_slice(b"Composer");
+
+        let ram = &mut bytes[0x100..0x10100];
+        ram[0x0200..0x0300].iter_mut().enumerate().for_each(|(idx, b)| *b = idx as u8);
+        ram[0x4000..0x4100].fill(fill);
+        bytes[0x10100..0x10180].iter_mut().enumerate().for_each(|(idx, b)| *b = idx as u8 ^ fill);
+        bytes[0x101C0..0x10200].fill(0xC0);
+
+        bytes
+    }
+
+    #[test]
+    fn roundtrip_keeps_snapshots_and_tags() {
+        let spcs = [spc_bytes(0x0400, 0x11, "Song A", 180), spc_bytes(0x0500, 0x22, "Song B", 90)];
+        let spc2 = Spc2File::from_spcs(spcs.iter().map(|bytes| bytes.as_slice())).unwrap();
+        // zero page, counting page and two filled pages
+        assert_eq!(spc2.blocks.len(), 4);
+
+        let parsed = Spc2File::from_bytes(&spc2.to_bytes()).unwrap();
+        assert_eq!(parsed.songs().len(), 2);
+        for (idx, bytes) in spcs.iter().enumerate() {
+            let expected = SpcFile::parse(bytes).unwrap();
+            let actual = parsed.spc_file(idx).unwrap();
+            assert_eq!(actual.ram, expected.ram);
+            assert_eq!(actual.regs, expected.regs);
+            assert_eq!(actual.extra_ram, expected.extra_ram);
+            assert_eq!((actual.pc, actual.a, actual.x, actual.y, actual.psw, actual.sp),
+                       (expected.pc, expected.a, expected.x, expected.y, expected.psw, expected.sp));
+            let (actual, expected) = (actual.tag.unwrap(), expected.tag.unwrap());
+            assert_eq!((actual.song_title, actual.game_title, actual.artist), (expected.song_title, expected.game_title, expected.artist));
+            assert_eq!((actual.length_secs, actual.fade_ms), (0, 0));
+        }
+    }
+
+    // Hand-built file with two songs and three blocks, written field by field from the layout above.
+    // It only guards the layout against regressions, it does not check the layout itself.
+    #[test]
+    fn parses_fixture() {
+        let spc2 = Spc2File::from_bytes(include_bytes!("../tests/fixtures/two_songs.sp2")).unwrap();
+        assert_eq!(spc2.songs().len(), 2);
+
+        let song = spc2.spc_file(0).unwrap();
+        assert_eq!((song.pc, song.a, song.x, song.y, song.psw, song.sp), (0x0400, 1, 2, 3, 4, 0xEF));
+        assert!(song.regs.iter().enumerate().all(|(idx, &b)| b == idx as u8));
+        assert!(song.extra_ram.iter().enumerate().all(|(idx, &b)| b == 0xC0 + idx as u8));
+        assert!(song.ram[0x0200..0x0300].iter().enumerate().all(|(idx, &b)| b == idx as u8));
+        assert!(song.ram[0xFF00..].iter().all(|&b| b == 0xAA));
+        assert!(song.ram[0x0300..0xFF00].iter().all(|&b| b == 0));
+
+        let tag = song.tag.unwrap();
+        assert_eq!(tag.song_title, "Song A");
+        assert_eq!(tag.game_title, "Game");
+        assert_eq!(tag.artist, "Composer");
+        assert_eq!(tag.comments, "Comment");
+        // written in the fixture, but not read until their offsets are checked
+        assert_eq!((tag.dumper.as_str(), tag.date.as_str()), ("", ""));
+        assert_eq!((tag.length_secs, tag.fade_ms), (0, 0));
+
+        let song = spc2.spc_file(1).unwrap();
+        assert_eq!(song.pc, 0x0500);
+        assert!(song.ram[0x1000..0x1100].iter().all(|&b| b == 0xAA));
+        assert_eq!(song.tag.unwrap().song_title, "Song B");
+    }
+
+    #[test]
+    fn rejects_missing_blocks() {
+        let bytes = include_bytes!("../tests/fixtures/two_songs.sp2");
+        let truncated = &bytes[..bytes.len() - BLOCK_SIZE];
+        assert!(matches!(Spc2File::from_bytes(truncated), Err(Spc700Error::TruncatedFile { .. })));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::error::{Result, Spc700Error};
+use crate::xid6::{Xid6, AMPLIFICATION};
+
+const SIGNATURE: &[u8] = b"SNES-SPC700 Sound File Data";
+const VERSION: &[u8] = b" v0.30";
+
+const TAG_FLAG_OFFSET: usize = 0x23;
+const REGISTER_OFFSET: usize = 0x25;
+const ID666_OFFSET: usize = 0x2E;
+const RAM_OFFSET: usize = 0x100;
+const DSP_REGISTER_OFFSET: usize = 0x10100;
+const EXTRA_RAM_OFFSET: usize = 0x101C0;
+pub const SPC_FILE_MIN_LEN: usize = 0x10200;
+
+// Snapshot stored in SPC file.
+#[derive(Clone)]
+pub(crate) struct SpcFile {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub psw: u8,
+    pub sp: u8,
+    pub ram: Box<[u8; 0x10000]>,
+    pub regs: [u8; 128],
+    pub extra_ram: [u8; 64],
+    pub tag: Option<Id666>,
+}
+
+// ID666 tag in SPC file header.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Id666 {
+    pub song_title: String,
+    pub game_title: String,
+    pub dumper: String,
+    pub comments: String,
+    pub date: String,
+    pub artist: String,
+    // seconds to play before fading out
+    pub length_secs: u32,
+    pub fade_ms: u32,
+    pub channel_disables: u8,
+    pub emulator: u8,
+    // mixing level in xid6, 0x10000 is unity gain
+    pub amplification: Option<u32>,
+}
+
+impl SpcFile {
+    pub fn parse(bytes: &[u8]) -> Result<SpcFile> {
+        let header_len = SIGNATURE.len() + VERSION.len();
+        if bytes.len() < header_len || &bytes[..SIGNATURE.len()] != SIGNATURE {
+            return Err(Spc700Error::BadHeader("signature is not found".to_string()));
+        }
+
+        let version = &bytes[SIGNATURE.len()..header_len];
+        if version != VERSION {
+            return Err(Spc700Error::UnsupportedVersion(String::from_utf8_lossy(version).trim().to_string()));
+        }
+
+        if bytes.len() < SPC_FILE_MIN_LEN {
+            return Err(Spc700Error::TruncatedFile { expected: SPC_FILE_MIN_LEN, actual: bytes.len() });
+        }
+
+        if bytes[0x21] != 0x1A || bytes[0x22] != 0x1A {
+            return Err(Spc700Error::BadHeader("invalid padding bytes".to_string()));
+        }
+
+        let regs = &bytes[REGISTER_OFFSET..];
+        let mut ram = Box::new([0; 0x10000]);
+        let mut dsp_regs = [0; 128];
+        let mut extra_ram = [0; 64];
+        ram.copy_from_slice(&bytes[RAM_OFFSET..RAM_OFFSET + 0x10000]);
+        dsp_regs.copy_from_slice(&bytes[DSP_REGISTER_OFFSET..DSP_REGISTER_OFFSET + 128]);
+        extra_ram.copy_from_slice(&bytes[EXTRA_RAM_OFFSET..EXTRA_RAM_OFFSET + 64]);
+
+        let mut tag = if bytes[TAG_FLAG_OFFSET] == 0x1A { Some(Id666::parse(&bytes[ID666_OFFSET..])) } else { None };
+        if let Some(xid6) = Xid6::parse(bytes) {
+            let tag = tag.get_or_insert_with(Id666::default);
+            tag.amplification = xid6.integer(AMPLIFICATION);
+        }
+
+        Ok(SpcFile {
+            pc: (regs[0] as u16) | ((regs[1] as u16) << 8),
+            a: regs[2],
+            x: regs[3],
+            y: regs[4],
+            psw: regs[5],
+            sp: regs[6],
+            ram,
+            regs: dsp_regs,
+            extra_ram,
+            tag,
+        })
+    }
+}
+
+impl Id666 {
+    // Gain of `amplification` in dB.
+    pub fn gain_db(&self) -> Option<f64> {
+        self.amplification
+            .filter(|&amp| amp != 0)
+            .map(|amp| 20.0 * (amp as f64 / 65536.0).log10())
+    }
+
+    // `tag` starts at 0x2E of SPC file.
+    fn parse(tag: &[u8]) -> Id666 {
+        let text = |offset: usize, len: usize| -> String {
+            let field = &tag[offset - ID666_OFFSET..offset - ID666_OFFSET + len];
+            let field = field.split(|&b| b == 0).next().unwrap_or(field);
+            String::from_utf8_lossy(field).trim().to_string()
+        };
+        let number = |offset: usize, len: usize| -> u32 {
+            text(offset, len).parse().unwrap_or(0)
+        };
+        let binary = |offset: usize, len: usize| -> u32 {
+            tag[offset - ID666_OFFSET..offset - ID666_OFFSET + len].iter().rev()
+                .fold(0, |acc, &b| (acc << 8) | b as u32)
+        };
+
+        // There is no flag to tell text format from binary format.
+        // Text format has only digits (or padding) in the length and fade fields.
+        let is_text = tag[0xA9 - ID666_OFFSET..0xB1 - ID666_OFFSET].iter()
+            .all(|&b| b.is_ascii_digit() || b == 0 || b == b' ');
+
+        if is_text {
+            Id666 {
+                song_title: text(0x2E, 32),
+                game_title: text(0x4E, 32),
+                dumper: text(0x6E, 16),
+                comments: text(0x7E, 32),
+                date: text(0x9E, 11),
+                artist: text(0xB1, 32),
+                length_secs: number(0xA9, 3),
+                fade_ms: number(0xAC, 5),
+                channel_disables: tag[0xD1 - ID666_OFFSET],
+                emulator: tag[0xD2 - ID666_OFFSET],
+                amplification: None,
+            }
+        } else {
+            let date = binary(0x9E, 4);
+            Id666 {
+                song_title: text(0x2E, 32),
+                game_title: text(0x4E, 32),
+                dumper: text(0x6E, 16),
+                comments: text(0x7E, 32),
+                date: if date == 0 { String::new() } else { format!("{:02}/{:02}/{:04}", (date >> 8) & 0xFF, date & 0xFF, date >> 16) },
+                artist: text(0xB0, 32),
+                length_secs: binary(0xA9, 3),
+                fade_ms: binary(0xAC, 4),
+                channel_disables: tag[0xD0 - ID666_OFFSET],
+                emulator: tag[0xD1 - ID666_OFFSET],
+                amplification: None,
+            }
+        }
+    }
+}
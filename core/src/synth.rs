@@ -0,0 +1,341 @@
+use std::time::Duration;
+
+use crate::dsp::{render_frames, DSP};
+use crate::error::Result;
+use crate::midi::{self, ChannelMessage, TimedMessage};
+use crate::timing::SAMPLE_RATE;
+
+const ORIGINAL_PITCH: f32 = 0x1000 as f32;
+const MAX_PITCH: f32 = 0x3FFF as f32;
+
+// Sample and envelope played for notes of a MIDI channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiInstrument {
+    pub srcn: u8,
+    // GAIN is used instead if bit 7 of `adsr1` is cleared
+    pub adsr1: u8,
+    pub adsr2: u8,
+    pub gain: u8,
+    // MIDI note played by pitch 0x1000
+    pub base_note: f32,
+}
+
+impl MidiInstrument {
+    // ADSR with the fastest attack, no decay and slow release, and middle C as base note.
+    pub fn new(srcn: u8) -> MidiInstrument {
+        MidiInstrument { srcn, adsr1: 0x8F, adsr2: 0xE0, gain: 0, base_note: 60.0 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    instrument: Option<MidiInstrument>,
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    // -0x2000..0x2000
+    bend: i16,
+    // in semitones
+    bend_range: u8,
+    // selected registered parameter number, data entry changes bend range only if this is 0
+    rpn: Option<u16>,
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel {
+            instrument: None,
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            bend: 0,
+            bend_range: 2,
+            rpn: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    base_note: f32,
+    // key off is already written
+    released: bool,
+    // sample index of note on, used to steal the oldest voice
+    started: u64,
+}
+
+// Plays a MIDI file with the DSP, using samples in RAM of the loaded song as instruments.
+// CPU does not run. Eight voices are allocated to notes dynamically,
+// and pitch, volume and envelope are written into DSP registers.
+// Echo and noise are disabled, and DSP events are not emitted.
+pub struct SpcSynth {
+    messages: Vec<TimedMessage>,
+    next: usize,
+    samples: u64,
+    channels: [Channel; 16],
+    voices: [Option<Voice>; 8],
+    key_off: u8,
+    default_base_note: f32,
+}
+
+impl SpcSynth {
+    // Takes over the DSP of the loaded song, so the song must be restarted to play it again.
+    pub fn new(midi: &[u8]) -> Result<SpcSynth> {
+        let messages = midi::parse_midi(midi)?;
+
+        let dsp = DSP::global();
+        dsp.set_record_events(false);
+        dsp.set_output_enable(true);
+        // FLG: no reset, no mute and echo buffer write disabled not to overwrite samples in RAM
+        let init = [
+            (0x6C, 0x20), (0x0C, 0x7F), (0x1C, 0x7F), (0x2C, 0x00), (0x3C, 0x00),
+            (0x0D, 0x00), (0x2D, 0x00), (0x3D, 0x00), (0x4D, 0x00), (0x5C, 0xFF),
+        ];
+        init.iter().for_each(|&(addr, data)| dsp.write_to_register(addr, data));
+
+        Ok(SpcSynth {
+            messages,
+            next: 0,
+            samples: 0,
+            channels: [Channel::default(); 16],
+            voices: [None; 8],
+            key_off: 0xFF,
+            default_base_note: 60.0,
+        })
+    }
+
+    // `channel` is 0..16. Without an instrument, the program number of the channel is used as SRCN.
+    pub fn set_instrument(&mut self, channel: u8, instrument: Option<MidiInstrument>) {
+        self.channels[channel as usize & 0x0F].instrument = instrument;
+    }
+
+    // Base note of channels without an instrument.
+    pub fn set_default_base_note(&mut self, note: f32) {
+        self.default_base_note = note;
+    }
+
+    // Time of the last message in the file.
+    pub fn length(&self) -> Duration {
+        let seconds = self.messages.last().map(|msg| msg.seconds).unwrap_or(0.0);
+        Duration::from_secs_f64(seconds)
+    }
+
+    // Whether all messages are played and all voices are released.
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.messages.len() && DSP::global().is_all_voices_released()
+    }
+
+    pub fn next_sample(&mut self) -> (i16, i16) {
+        while let Some(msg) = self.messages.get(self.next) {
+            if (msg.seconds * SAMPLE_RATE as f64) as u64 > self.samples {
+                break;
+            }
+
+            let message = msg.message;
+            self.next += 1;
+            self.apply(message);
+        }
+
+        let dsp = DSP::global();
+        dsp.cycles(64);
+        dsp.flush();
+        self.samples += 1;
+
+        (dsp.sample_left_out(), dsp.sample_right_out())
+    }
+
+    // Fills `buffer` with interleaved stereo samples and returns the number of rendered frames.
+    pub fn render(&mut self, buffer: &mut [i16]) -> usize {
+        render_frames(buffer, || self.next_sample())
+    }
+
+    fn apply(&mut self, message: ChannelMessage) {
+        match message {
+            ChannelMessage::NoteOn { channel, key, velocity } => self.note_on(channel, key, velocity),
+            ChannelMessage::NoteOff { channel, key } => {
+                self.release(|voice| voice.channel == channel && voice.key == key && !voice.released)
+            }
+            ChannelMessage::Program { channel, program } => self.channels[channel as usize].program = program,
+            ChannelMessage::PitchBend { channel, value } => {
+                self.channels[channel as usize].bend = value;
+                self.update_voices(channel);
+            }
+            ChannelMessage::Control { channel, control, value } => self.control(channel, control, value),
+        }
+    }
+
+    fn control(&mut self, channel: u8, control: u8, value: u8) {
+        let ch = &mut self.channels[channel as usize];
+        match control {
+            0x06 if ch.rpn == Some(0) => ch.bend_range = value,
+            0x07 => ch.volume = value,
+            0x0A => ch.pan = value,
+            0x0B => ch.expression = value,
+            0x64 => ch.rpn = Some((ch.rpn.unwrap_or(0) & 0x3F80) | value as u16),
+            0x65 => ch.rpn = Some((ch.rpn.unwrap_or(0) & 0x007F) | ((value as u16) << 7)),
+            // reset all controllers
+            0x79 => {
+                *ch = Channel { instrument: ch.instrument, program: ch.program, ..Channel::default() };
+            }
+            // all sound off and all notes off
+            0x78 | 0x7B => {
+                self.release(|voice| voice.channel == channel);
+                return;
+            }
+            _ => return,
+        }
+
+        self.update_voices(channel);
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let ch = self.channels[channel as usize];
+        let instrument = ch.instrument.unwrap_or(MidiInstrument {
+            base_note: self.default_base_note,
+            ..MidiInstrument::new(ch.program)
+        });
+
+        // retriggered note uses the same voice
+        let same = self.voices.iter().position(|voice| voice.is_some_and(|v| v.channel == channel && v.key == key));
+        let idx = same.unwrap_or_else(|| self.allocate());
+        let voice = Voice { channel, key, velocity, base_note: instrument.base_note, released: false, started: self.samples };
+        self.voices[idx] = Some(voice);
+
+        let dsp = DSP::global();
+        let base = idx << 4;
+        self.key_off &= !(1 << idx);
+        dsp.write_to_register(0x5C, self.key_off);
+        dsp.write_to_register(base | 0x4, instrument.srcn);
+        dsp.write_to_register(base | 0x5, instrument.adsr1);
+        dsp.write_to_register(base | 0x6, instrument.adsr2);
+        dsp.write_to_register(base | 0x7, instrument.gain);
+        self.write_voice(idx, &voice);
+        dsp.write_to_register(0x4C, 1 << idx);
+    }
+
+    // Prefers a silent voice, then the oldest released voice, and steals the oldest voice at last.
+    fn allocate(&self) -> usize {
+        let dsp = DSP::global();
+        let oldest = |released: bool| {
+            self.voices.iter().enumerate()
+                .filter_map(|(idx, voice)| voice.filter(|v| v.released == released).map(|v| (idx, v.started)))
+                .min_by_key(|&(_, started)| started)
+                .map(|(idx, _)| idx)
+        };
+
+        self.voices.iter().enumerate()
+            .position(|(idx, voice)| voice.is_none_or(|v| v.released) && dsp.is_voice_released(idx))
+            .or_else(|| oldest(true))
+            .or_else(|| oldest(false))
+            .unwrap_or(0)
+    }
+
+    fn release(&mut self, is_target: impl Fn(&Voice) -> bool) {
+        let mut key_off = self.key_off;
+        self.voices.iter_mut().enumerate()
+            .filter_map(|(idx, voice)| voice.as_mut().filter(|v| is_target(v)).map(|v| (idx, v)))
+            .for_each(|(idx, voice)| {
+                voice.released = true;
+                key_off |= 1 << idx;
+            });
+
+        if key_off != self.key_off {
+            self.key_off = key_off;
+            DSP::global().write_to_register(0x5C, key_off);
+        }
+    }
+
+    // Reflects controllers of `channel` into voices playing it.
+    fn update_voices(&self, channel: u8) {
+        self.voices.iter().enumerate()
+            .filter_map(|(idx, voice)| voice.filter(|v| v.channel == channel).map(|v| (idx, v)))
+            .for_each(|(idx, voice)| self.write_voice(idx, &voice));
+    }
+
+    fn write_voice(&self, idx: usize, voice: &Voice) {
+        let ch = &self.channels[voice.channel as usize];
+        let bend = ch.bend as f32 / 0x2000 as f32 * ch.bend_range as f32;
+        let pitch = ORIGINAL_PITCH * ((voice.key as f32 + bend - voice.base_note) / 12.0).exp2();
+        let pitch = pitch.clamp(0.0, MAX_PITCH) as u16;
+
+        // velocity has square curve
+        let level = (voice.velocity as f32 / 127.0).powi(2) * (ch.volume as f32 / 127.0) * (ch.expression as f32 / 127.0);
+        let pan = ch.pan as f32 / 127.0;
+        let left = (127.0 * level * (2.0 * (1.0 - pan)).min(1.0)) as u8;
+        let right = (127.0 * level * (2.0 * pan).min(1.0)) as u8;
+
+        let dsp = DSP::global();
+        let base = idx << 4;
+        dsp.write_to_register(base, left);
+        dsp.write_to_register(base | 0x1, right);
+        dsp.write_to_register(base | 0x2, (pitch & 0xFF) as u8);
+        dsp.write_to_register(base | 0x3, (pitch >> 8) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Spc700;
+    use crate::testing::{lock_globals, spc_file, IDLE};
+
+    // format 0 with an empty track
+    const EMPTY_MIDI: &[u8] = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x04\0\xFF\x2F\0";
+    // release takes 256 samples from the top level
+    const RELEASE_SAMPLES: usize = 300;
+
+    fn run(synth: &mut SpcSynth, samples: usize) {
+        (0..samples).for_each(|_| { synth.next_sample(); });
+    }
+
+    fn voice_of(synth: &SpcSynth, key: u8) -> Option<usize> {
+        synth.voices.iter().position(|voice| voice.is_some_and(|v| v.key == key && !v.released))
+    }
+
+    #[test]
+    fn allocates_silent_then_released_then_oldest_voice() {
+        let _lock = lock_globals();
+        let mut emulator = Spc700::new();
+        emulator.load_from_bytes(&spc_file(IDLE, &[])).unwrap();
+        let mut synth = emulator.synth(EMPTY_MIDI).unwrap();
+
+        for key in 60..68 {
+            synth.note_on(0, key, 100);
+            run(&mut synth, 10);
+        }
+        assert_eq!((60..68).map(|key| voice_of(&synth, key)).collect::<Vec<_>>(), (0..8).map(Some).collect::<Vec<_>>());
+
+        // released voices are reused from the oldest note while they are still sounding
+        synth.apply(ChannelMessage::NoteOff { channel: 0, key: 65 });
+        synth.apply(ChannelMessage::NoteOff { channel: 0, key: 62 });
+        run(&mut synth, 10);
+        assert!(!DSP::global().is_voice_released(2));
+        synth.note_on(0, 70, 100);
+        synth.note_on(0, 71, 100);
+        assert_eq!((voice_of(&synth, 70), voice_of(&synth, 71)), (Some(2), Some(5)));
+
+        // all voices are held, so the oldest note is stolen
+        synth.note_on(0, 72, 100);
+        assert_eq!(voice_of(&synth, 72), Some(0));
+        assert_eq!(voice_of(&synth, 60), None);
+        run(&mut synth, 10);
+
+        // a silent voice is taken before an older released voice
+        synth.apply(ChannelMessage::NoteOff { channel: 0, key: 66 });
+        run(&mut synth, RELEASE_SAMPLES);
+        synth.apply(ChannelMessage::NoteOff { channel: 0, key: 61 });
+        run(&mut synth, 10);
+        assert!(DSP::global().is_voice_released(6) && !DSP::global().is_voice_released(1));
+        synth.note_on(0, 73, 100);
+        assert_eq!(voice_of(&synth, 73), Some(6));
+
+        // retriggered note keeps its voice
+        synth.note_on(0, 61, 100);
+        assert_eq!(voice_of(&synth, 61), Some(1));
+    }
+}
@@ -0,0 +1,100 @@
+// Helpers shared by tests.
+use std::sync::{Mutex, MutexGuard};
+
+use crate::dsp::{ADSRMode, DspState, VoiceState};
+use crate::spc_file::SPC_FILE_MIN_LEN;
+
+// RAM and DSP are globals, so tests using them must not run at the same time.
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+pub(crate) fn lock_globals() -> MutexGuard<'static, ()> {
+    GLOBALS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub(crate) const PROGRAM_ADDR: usize = 0x0200;
+// DIR is 0x0F, and SRCN 0 starts and loops at 0x1000
+pub(crate) const SAMPLE_TABLE: u8 = 0x0F;
+pub(crate) const SAMPLE_ADDR: usize = 0x1000;
+
+// SPC file whose CPU runs `program` at 0x0200, with DSP registers set by `regs`.
+// SRCN 0 is a looped square wave of 16 samples.
+pub(crate) fn spc_file(program: &[u8], regs: &[(u8, u8)]) -> Vec<u8> {
+    let mut bytes = vec![0; SPC_FILE_MIN_LEN];
+    bytes[..33].copy_from_slice(b"SNES-SPC700 Sound File Data v0.30");
+    bytes[0x21] = 0x1A;
+    bytes[0x22] = 0x1A;
+    // no ID666 tag
+    bytes[0x23] = 0x1B;
+    bytes[0x25..0x27].copy_from_slice(&(PROGRAM_ADDR as u16).to_le_bytes());
+    // SP
+    bytes[0x2B] = 0xEF;
+
+    let ram = &mut bytes[0x100..0x10100];
+    ram[PROGRAM_ADDR..PROGRAM_ADDR + program.len()].copy_from_slice(program);
+    // TEST register
+    ram[0xF0] = 0x0A;
+    let entry = (SAMPLE_TABLE as usize) << 8;
+    ram[entry..entry + 4].copy_from_slice(&[0x00, 0x10, 0x00, 0x10]);
+    // range 11, filter 0, loop and end
+    ram[SAMPLE_ADDR] = 0xB3;
+    ram[SAMPLE_ADDR + 1..SAMPLE_ADDR + 5].fill(0x77);
+    ram[SAMPLE_ADDR + 5..SAMPLE_ADDR + 9].fill(0x99);
+
+    let dsp = &mut bytes[0x10100..0x10180];
+    dsp[0x5D] = SAMPLE_TABLE;
+    // mute until the registers below clear it
+    dsp[0x6C] = 0x60;
+    regs.iter().for_each(|&(addr, data)| dsp[addr as usize] = data);
+
+    bytes
+}
+
+// Branches to itself forever.
+pub(crate) const IDLE: &[u8] = &[0x2F, 0xFE];
+
+// Voice keyed on with `srcn`, centered at volume 64.
+pub(crate) fn voice_state(key_on: bool, srcn: u8, pitch: u16, envelope_level: i16) -> VoiceState {
+    VoiceState {
+        key_on,
+        key_off: false,
+        adsr_mode: ADSRMode::Sustain,
+        envelope_level,
+        pitch,
+        srcn,
+        adsr: 0x8FE0,
+        gain: 0,
+        volume_left: 64,
+        volume_right: 64,
+        brr_addr: 0,
+        loop_addr: 0,
+        is_loop: false,
+        voice_end: false,
+        echo_enable: false,
+        noise_enable: false,
+        pmon_enable: false,
+        out_left: 0,
+        out_right: 0,
+    }
+}
+
+// DSP whose first voices are `voices`, and the others are silent.
+pub(crate) fn dsp_state(voices: &[VoiceState]) -> DspState {
+    let mut all = [voice_state(false, 0, 0x1000, 0); 8];
+    all[..voices.len()].copy_from_slice(voices);
+    DspState {
+        voices: all,
+        master_volume_left: 127,
+        master_volume_right: 127,
+        echo_volume_left: 0,
+        echo_volume_right: 0,
+        echo_feedback: 0,
+        fir_coefficients: [0; 8],
+        echo_buffer_addr: 0,
+        echo_buffer_len: 0,
+        echo_write_enable: false,
+        is_mute: false,
+        sample_table_addr: (SAMPLE_TABLE as u16) << 8,
+        out_left: 0,
+        out_right: 0,
+    }
+}
@@ -0,0 +1,13 @@
+use std::time::Duration;
+
+// DSP generates one stereo sample at this rate.
+pub const SAMPLE_RATE: u32 = 32000;
+
+// Number of samples played in `duration`, rounded down.
+pub fn duration_to_samples(duration: Duration) -> u64 {
+    duration.as_micros() as u64 * SAMPLE_RATE as u64 / 1_000_000
+}
+
+pub fn samples_to_duration(samples: u64) -> Duration {
+    Duration::from_micros(samples * 1_000_000 / SAMPLE_RATE as u64)
+}
@@ -0,0 +1,116 @@
+// Extended ID666 chunk following the SPC image.
+//
+// layout:
+//   0x00  "xid6"
+//   0x04  size of sub-chunks (u32)
+//   0x08  sub-chunks
+//     0x00  id
+//     0x01  type (0: data in header, 1: string, 4: integer)
+//     0x02  data (type 0) or length of following data (u16)
+//     0x04  data, padded to 4 bytes
+use std::ops::RangeInclusive;
+
+use crate::spc_file::SPC_FILE_MIN_LEN;
+
+const SIGNATURE: &[u8] = b"xid6";
+const HEADER_SIZE: usize = 8;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_INTEGER: u8 = 4;
+
+// mixing level as 16.16 fixed point, 0x10000 is unity gain
+pub(crate) const AMPLIFICATION: u8 = 0x36;
+// mixing levels allowed by the xid6 specification, about -6dB to +18dB
+pub(crate) const AMPLIFICATION_RANGE: RangeInclusive<u32> = 0x8000..=0x80000;
+
+struct Xid6Item {
+    id: u8,
+    kind: u8,
+    header_data: u16,
+    data: Vec<u8>,
+}
+
+// Sub-chunks are kept as they are, so unknown items survive rewriting.
+pub(crate) struct Xid6 {
+    items: Vec<Xid6Item>,
+}
+
+impl Xid6 {
+    pub fn new() -> Xid6 {
+        Xid6 { items: Vec::new() }
+    }
+
+    // `bytes` is a whole SPC file. Returns None if it has no xid6 chunk.
+    // Broken sub-chunks at the end are ignored.
+    pub fn parse(bytes: &[u8]) -> Option<Xid6> {
+        let chunk = bytes.get(SPC_FILE_MIN_LEN..)?;
+        if chunk.len() < HEADER_SIZE || !chunk.starts_with(SIGNATURE) {
+            return None;
+        }
+
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        let body = &chunk[HEADER_SIZE..(HEADER_SIZE + size).min(chunk.len())];
+
+        let mut items = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= body.len() {
+            let id = body[pos];
+            let kind = body[pos + 1];
+            let header_data = u16::from_le_bytes([body[pos + 2], body[pos + 3]]);
+            pos += 4;
+
+            let data = if kind == TYPE_DATA {
+                Vec::new()
+            } else {
+                let len = header_data as usize;
+                match body.get(pos..pos + len) {
+                    Some(data) => data.to_vec(),
+                    None => break,
+                }
+            };
+            pos += padded_len(data.len());
+
+            items.push(Xid6Item { id, kind, header_data, data });
+        }
+
+        Some(Xid6 { items })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for item in self.items.iter() {
+            body.push(item.id);
+            body.push(item.kind);
+            body.extend_from_slice(&item.header_data.to_le_bytes());
+            body.extend_from_slice(&item.data);
+            body.resize(body.len() + padded_len(item.data.len()) - item.data.len(), 0);
+        }
+
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    pub fn integer(&self, id: u8) -> Option<u32> {
+        self.items.iter()
+            .find(|item| item.id == id && item.kind == TYPE_INTEGER && item.data.len() == 4)
+            .map(|item| u32::from_le_bytes([item.data[0], item.data[1], item.data[2], item.data[3]]))
+    }
+
+    pub fn set_integer(&mut self, id: u8, value: u32) {
+        self.items.retain(|item| item.id != id);
+        self.items.push(Xid6Item { id, kind: TYPE_INTEGER, header_data: 4, data: value.to_le_bytes().to_vec() });
+    }
+}
+
+// Replaces xid6 chunk of SPC file `bytes`.
+pub(crate) fn replace_xid6(bytes: &[u8], xid6: &Xid6) -> Vec<u8> {
+    let mut replaced = bytes[..SPC_FILE_MIN_LEN.min(bytes.len())].to_vec();
+    replaced.extend_from_slice(&xid6.to_bytes());
+    replaced
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
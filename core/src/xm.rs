@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+
+use crate::dsp::{BrrSample, DspState};
+use crate::event::{DspEvent, DspEventKind};
+use crate::timing::SAMPLE_RATE;
+
+const CHANNELS: usize = 8;
+const ROWS_PER_PATTERN: usize = 64;
+const MAX_PATTERNS: usize = 256;
+const MAX_INSTRUMENTS: usize = 128;
+const HEADER_SIZE: u32 = 276;
+const INSTRUMENT_HEADER_SIZE: u32 = 263;
+const SAMPLE_HEADER_SIZE: u32 = 40;
+
+// XM plays C-4 (note 49) of a sample without relative note at this rate
+const C4_RATE: f64 = 8363.0;
+const C4: f64 = 49.0;
+const KEY_OFF: u8 = 97;
+const ORIGINAL_PITCH: f64 = 0x1000 as f64;
+
+// key on intervals differing by this are regarded as the same
+const TIMING_TOLERANCE: u64 = 2;
+// rows are searched in this range (10ms to 500ms)
+const MIN_ROW_SAMPLES: f64 = 320.0;
+const MAX_ROW_SAMPLES: f64 = 16000.0;
+// used if no rhythm is found (16th note at 120 BPM)
+const DEFAULT_ROW_SAMPLES: f64 = 4000.0;
+// row length is accepted if intervals of this ratio fit its multiples
+const ROW_FIT_RATIO: f64 = 0.9;
+const MAX_ROW_CANDIDATES: usize = 16;
+
+#[derive(Clone, Copy)]
+enum VoiceEvent {
+    NoteOn { instrument: usize, note: u8 },
+    NoteOff,
+    // 0..=64
+    Volume(u8),
+    // 0 (left) ..= 255 (right)
+    Pan(u8),
+}
+
+#[derive(Default)]
+struct VoiceRecord {
+    events: Vec<(u64, VoiceEvent)>,
+    volume: Option<u8>,
+    pan: Option<u8>,
+    is_playing: bool,
+    key_on: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    note: u8,
+    instrument: u8,
+    volume: Option<u8>,
+    pan: Option<u8>,
+}
+
+// Transcribes DSP events and states into an XM module.
+// BRR samples are decoded from the sample directory when they are keyed on for the first time,
+// and notes, volumes (including envelope) and panning of each voice are quantized into rows.
+pub struct XmRecorder {
+    voices: [VoiceRecord; CHANNELS],
+    instruments: Vec<BrrSample>,
+    instrument_idx: HashMap<u8, usize>,
+    key_ons: Vec<u64>,
+}
+
+impl XmRecorder {
+    pub fn new() -> XmRecorder {
+        XmRecorder {
+            voices: Default::default(),
+            instruments: Vec::new(),
+            instrument_idx: HashMap::new(),
+            key_ons: Vec::new(),
+        }
+    }
+
+    // `events` are emitted while `sample` is generated, and `state` is taken after that.
+    pub fn process(&mut self, sample: u64, events: &[DspEvent], state: &DspState) {
+        for event in events {
+            if let DspEventKind::KeyOn { voice } = event.kind {
+                self.voices[voice].key_on = true;
+                self.key_ons.push(sample);
+            }
+        }
+
+        for (idx, voice) in state.voices.iter().enumerate() {
+            if std::mem::take(&mut self.voices[idx].key_on) {
+                // the note is dropped rather than played by a wrong instrument
+                let Some(instrument) = self.instrument(state.sample_table_addr, voice.srcn) else {
+                    if std::mem::take(&mut self.voices[idx].is_playing) {
+                        self.voices[idx].events.push((sample, VoiceEvent::NoteOff));
+                    }
+                    continue;
+                };
+                let note = C4 + 12.0 * (voice.pitch.max(1) as f64 / ORIGINAL_PITCH).log2();
+                let note = note.round().clamp(1.0, 96.0) as u8;
+
+                self.voices[idx].events.push((sample, VoiceEvent::NoteOn { instrument, note }));
+                self.voices[idx].volume = None;
+                self.voices[idx].is_playing = true;
+            } else if self.voices[idx].is_playing && !voice.key_on {
+                self.voices[idx].events.push((sample, VoiceEvent::NoteOff));
+                self.voices[idx].is_playing = false;
+            }
+
+            if !self.voices[idx].is_playing {
+                continue;
+            }
+
+            let volume = (voice.volume_left as i32).abs().max((voice.volume_right as i32).abs()) as f64 / 128.0;
+            let volume = (volume * voice.envelope_level as f64 / 0x7FF as f64 * 64.0).round() as u8;
+            if self.voices[idx].volume != Some(volume) {
+                self.voices[idx].volume = Some(volume);
+                self.voices[idx].events.push((sample, VoiceEvent::Volume(volume)));
+            }
+
+            let left = (voice.volume_left as i32).abs();
+            let right = (voice.volume_right as i32).abs();
+            if left + right > 0 {
+                let pan = (128 + 127 * (right - left) / (left + right)) as u8;
+                if self.voices[idx].pan != Some(pan) {
+                    self.voices[idx].pan = Some(pan);
+                    self.voices[idx].events.push((sample, VoiceEvent::Pan(pan)));
+                }
+            }
+        }
+    }
+
+    // Quantizes recorded events until `end` into patterns, and returns XM module titled `title`.
+    pub fn finish(&self, end: u64, title: &str) -> Vec<u8> {
+        let row_samples = detect_row_samples(&self.key_ons);
+        let (speed, bpm) = speed_and_bpm(row_samples);
+
+        let max_rows = MAX_PATTERNS * ROWS_PER_PATTERN;
+        let rows = ((end as f64 / row_samples).ceil() as usize).clamp(1, max_rows);
+        let mut grid = vec![[Cell::default(); CHANNELS]; rows];
+        for (channel, voice) in self.voices.iter().enumerate() {
+            for &(sample, event) in voice.events.iter() {
+                let row = (sample as f64 / row_samples).round() as usize;
+                let cell = match grid.get_mut(row) {
+                    Some(cells) => &mut cells[channel],
+                    None => continue,
+                };
+
+                match event {
+                    VoiceEvent::NoteOn { instrument, note } => {
+                        cell.note = note;
+                        cell.instrument = (instrument + 1) as u8;
+                    }
+                    // note on in the same row takes priority
+                    VoiceEvent::NoteOff => if cell.note == 0 { cell.note = KEY_OFF },
+                    VoiceEvent::Volume(volume) => cell.volume = Some(volume),
+                    VoiceEvent::Pan(pan) => cell.pan = Some(pan),
+                }
+            }
+        }
+
+        let patterns = grid.chunks(ROWS_PER_PATTERN).collect::<Vec<_>>();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"Extended Module: ");
+        write_name(&mut bytes, title, 20);
+        bytes.push(0x1A);
+        write_name(&mut bytes, "spc700-rs", 20);
+        bytes.extend_from_slice(&0x0104u16.to_le_bytes());
+
+        bytes.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+        bytes.extend_from_slice(&(patterns.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&(CHANNELS as u16).to_le_bytes());
+        bytes.extend_from_slice(&(patterns.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.instruments.len() as u16).to_le_bytes());
+        // linear frequency table
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&speed.to_le_bytes());
+        bytes.extend_from_slice(&bpm.to_le_bytes());
+        let mut orders = [0; 256];
+        orders.iter_mut().zip(0..patterns.len()).for_each(|(order, idx)| *order = idx as u8);
+        bytes.extend_from_slice(&orders);
+
+        patterns.iter().for_each(|pattern| write_pattern(&mut bytes, pattern));
+        self.instruments.iter().for_each(|instrument| write_instrument(&mut bytes, instrument));
+
+        bytes
+    }
+
+    // XM has at most 128 instruments, so SRCNs keyed on after that have none.
+    fn instrument(&mut self, table_addr: u16, srcn: u8) -> Option<usize> {
+        if let Some(&idx) = self.instrument_idx.get(&srcn) {
+            return Some(idx);
+        }
+        if self.instruments.len() >= MAX_INSTRUMENTS {
+            return None;
+        }
+
+        self.instruments.push(BrrSample::decode(table_addr, srcn));
+        self.instrument_idx.insert(srcn, self.instruments.len() - 1);
+        Some(self.instruments.len() - 1)
+    }
+}
+
+impl Default for XmRecorder {
+    fn default() -> XmRecorder {
+        XmRecorder::new()
+    }
+}
+
+// Finds the longest row whose multiples fit most intervals of key on.
+fn detect_row_samples(key_ons: &[u64]) -> f64 {
+    let mut times = key_ons.to_vec();
+    times.sort_unstable();
+    times.dedup_by(|later, earlier| *later - *earlier <= TIMING_TOLERANCE);
+
+    let intervals = times.windows(2)
+        .map(|pair| (pair[1] - pair[0]) as f64)
+        .filter(|&interval| interval >= MIN_ROW_SAMPLES)
+        .collect::<Vec<_>>();
+
+    let mut histogram = HashMap::<u64, usize>::new();
+    intervals.iter().for_each(|&interval| *histogram.entry(interval as u64 / TIMING_TOLERANCE).or_default() += 1);
+    let mut frequent = histogram.into_iter().collect::<Vec<_>>();
+    frequent.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let candidates = frequent.iter()
+        .take(MAX_ROW_CANDIDATES)
+        .flat_map(|&(interval, _)| (1..=16).map(move |div| (interval * TIMING_TOLERANCE) as f64 / div as f64))
+        .filter(|&len| (MIN_ROW_SAMPLES..=MAX_ROW_SAMPLES).contains(&len));
+
+    let fits = |len: f64| -> usize {
+        let tolerance = (TIMING_TOLERANCE as f64).max(len / 16.0);
+        intervals.iter().filter(|&&interval| (interval - (interval / len).round() * len).abs() <= tolerance).count()
+    };
+
+    let required = (intervals.len() as f64 * ROW_FIT_RATIO).ceil() as usize;
+    candidates
+        .map(|len| (fits(len), len))
+        .max_by(|a, b| {
+            let key = |(fit, len): (usize, f64)| if fit >= required { (1, len) } else { (0, fit as f64) };
+            key(*a).partial_cmp(&key(*b)).unwrap()
+        })
+        .map(|(_, len)| len)
+        .unwrap_or(DEFAULT_ROW_SAMPLES)
+}
+
+// A tick of XM is 2.5 / BPM seconds, and a row has `speed` ticks.
+fn speed_and_bpm(row_samples: f64) -> (u16, u16) {
+    let row_seconds = row_samples / SAMPLE_RATE as f64;
+    (1..=31u16)
+        .map(|speed| (speed, 2.5 * speed as f64 / row_seconds))
+        .filter(|(_, bpm)| (32.0..=255.0).contains(bpm))
+        .min_by(|a, b| {
+            let error = |bpm: f64| (bpm.round() - bpm).abs() / bpm;
+            error(a.1).partial_cmp(&error(b.1)).unwrap()
+        })
+        .map(|(speed, bpm)| (speed, bpm.round() as u16))
+        .unwrap_or(if row_seconds < 1.0 { (1, 255) } else { (31, 32) })
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str, len: usize) {
+    let mut field = name.bytes().filter(u8::is_ascii).take(len).collect::<Vec<_>>();
+    field.resize(len, 0);
+    bytes.extend_from_slice(&field);
+}
+
+// Cells are packed, and empty fields are omitted.
+fn write_pattern(bytes: &mut Vec<u8>, rows: &[[Cell; CHANNELS]]) {
+    let mut data = Vec::new();
+    for cell in rows.iter().flatten() {
+        let mut flags = 0x80;
+        let mut fields = Vec::new();
+        if cell.note != 0 {
+            flags |= 0x01;
+            fields.push(cell.note);
+        }
+        if cell.instrument != 0 {
+            flags |= 0x02;
+            fields.push(cell.instrument);
+        }
+        if let Some(volume) = cell.volume {
+            flags |= 0x04;
+            fields.push(0x10 + volume);
+        }
+        // effect 8xx sets panning
+        if let Some(pan) = cell.pan {
+            flags |= 0x18;
+            fields.extend_from_slice(&[0x08, pan]);
+        }
+
+        data.push(flags);
+        data.extend_from_slice(&fields);
+    }
+
+    bytes.extend_from_slice(&9u32.to_le_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&(rows.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&data);
+}
+
+// Each instrument has a 16bit sample tuned to play pitch 0x1000 as C-4.
+fn write_instrument(bytes: &mut Vec<u8>, sample: &BrrSample) {
+    bytes.extend_from_slice(&INSTRUMENT_HEADER_SIZE.to_le_bytes());
+    write_name(bytes, &format!("SRCN {:02X}", sample.srcn), 22);
+    bytes.push(0);
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&SAMPLE_HEADER_SIZE.to_le_bytes());
+    // keymap, envelopes, vibrato and fadeout are unused
+    bytes.resize(bytes.len() + (INSTRUMENT_HEADER_SIZE as usize - 33), 0);
+
+    let tuning = 12.0 * (SAMPLE_RATE as f64 / C4_RATE).log2();
+    let relative_note = tuning.floor();
+    let finetune = ((tuning - relative_note) * 128.0).round() as i8;
+
+    let (kind, loop_start, loop_len) = match sample.loop_start {
+        Some(start) if start < sample.data.len() => (0x11, start, sample.data.len() - start),
+        _ => (0x10, 0, 0),
+    };
+    bytes.extend_from_slice(&(sample.data.len() as u32 * 2).to_le_bytes());
+    bytes.extend_from_slice(&(loop_start as u32 * 2).to_le_bytes());
+    bytes.extend_from_slice(&(loop_len as u32 * 2).to_le_bytes());
+    bytes.push(64);
+    bytes.push(finetune as u8);
+    bytes.push(kind);
+    bytes.push(128);
+    bytes.push(relative_note as i8 as u8);
+    bytes.push(0);
+    write_name(bytes, &format!("{:04X}", sample.start_addr), 22);
+
+    // sample data is delta encoded
+    let mut last = 0i16;
+    for &value in sample.data.iter() {
+        bytes.extend_from_slice(&value.wrapping_sub(last).to_le_bytes());
+        last = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::ram::Ram;
+    use crate::testing::{dsp_state, lock_globals, voice_state, SAMPLE_ADDR, SAMPLE_TABLE};
+
+    const PATTERN_ADDR: usize = 60 + HEADER_SIZE as usize;
+
+    fn read_u16(bytes: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([bytes[pos], bytes[pos + 1]])
+    }
+
+    // (note, instrument) of all cells in patterns.
+    fn cells(bytes: &[u8]) -> Vec<(u8, u8)> {
+        let mut cells = Vec::new();
+        let mut pos = PATTERN_ADDR;
+        for _ in 0..read_u16(bytes, 70) {
+            let data_len = read_u16(bytes, pos + 7) as usize;
+            let data = &bytes[pos + 9..pos + 9 + data_len];
+            pos += 9 + data_len;
+
+            let mut idx = 0;
+            while idx < data.len() {
+                let flags = data[idx];
+                idx += 1;
+                let mut field = |bit: u8| if (flags & bit) != 0 { idx += 1; data[idx - 1] } else { 0 };
+                let note = field(0x01);
+                let instrument = field(0x02);
+                (0..3).for_each(|bit| { field(0x04 << bit); });
+                cells.push((note, instrument));
+            }
+        }
+
+        cells
+    }
+
+    #[test]
+    fn row_is_the_longest_fitting_interval() {
+        // quarter notes of 4000 samples with 1 sample jitter, and a half note
+        let key_ons = [0, 4000, 8001, 12000, 20000, 24000, 24001];
+        assert_eq!(detect_row_samples(&key_ons), 4000.0);
+
+        // intervals shorter than 10ms are ignored
+        assert_eq!(detect_row_samples(&[0, 100, 200]), DEFAULT_ROW_SAMPLES);
+        assert_eq!(detect_row_samples(&[]), DEFAULT_ROW_SAMPLES);
+    }
+
+    #[test]
+    fn speed_and_bpm_give_row_length() {
+        for row_samples in [MIN_ROW_SAMPLES, 1000.0, DEFAULT_ROW_SAMPLES, 5333.0, MAX_ROW_SAMPLES] {
+            let (speed, bpm) = speed_and_bpm(row_samples);
+            assert!((1..=31).contains(&speed) && (32..=255).contains(&bpm));
+
+            let row = 2.5 * speed as f64 / bpm as f64 * SAMPLE_RATE as f64;
+            assert!((row - row_samples).abs() / row_samples < 0.01, "{} {} {}", row_samples, speed, bpm);
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let recorder = XmRecorder::new();
+        let rows = ROWS_PER_PATTERN as u64 + 1;
+        let bytes = recorder.finish(DEFAULT_ROW_SAMPLES as u64 * rows, "Title");
+
+        assert_eq!(&bytes[..17], b"Extended Module: ");
+        assert_eq!(&bytes[17..37], b"Title\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(bytes[37], 0x1A);
+        assert_eq!(&bytes[38..47], b"spc700-rs");
+        assert_eq!(read_u16(&bytes, 58), 0x0104);
+        assert_eq!(&bytes[60..64], &HEADER_SIZE.to_le_bytes());
+        // song length, restart, channels, patterns, instruments and flags
+        let fields = (64..76).step_by(2).map(|pos| read_u16(&bytes, pos)).collect::<Vec<_>>();
+        assert_eq!(fields, [2, 0, 8, 2, 0, 1]);
+        assert_eq!((read_u16(&bytes, 76), read_u16(&bytes, 78)), speed_and_bpm(DEFAULT_ROW_SAMPLES));
+        assert_eq!(&bytes[80..83], &[0, 1, 0]);
+
+        // the second pattern has one row, and empty cells are packed into a flag byte
+        assert_eq!(&bytes[PATTERN_ADDR..PATTERN_ADDR + 5], &[9, 0, 0, 0, 0]);
+        assert_eq!(read_u16(&bytes, PATTERN_ADDR + 5), 64);
+        assert_eq!(read_u16(&bytes, PATTERN_ADDR + 7), 64 * 8);
+        let second = PATTERN_ADDR + 9 + 64 * 8;
+        assert_eq!(read_u16(&bytes, second + 5), 1);
+        assert_eq!(bytes.len(), second + 9 + 8);
+    }
+
+    #[test]
+    fn notes_without_instrument_are_dropped() {
+        let _globals = lock_globals();
+        let mut ram = [0; 0x10000];
+        let table = (SAMPLE_TABLE as usize) << 8;
+        (0..256).for_each(|srcn| ram[table + srcn * 4..table + srcn * 4 + 4].copy_from_slice(&[0x00, 0x10, 0x00, 0x10]));
+        // end block without loop
+        ram[SAMPLE_ADDR] = 0x01;
+        Ram::init(&ram, &[0; 64]);
+
+        let mut recorder = XmRecorder::new();
+        for srcn in 0..=255u8 {
+            let sample = srcn as u64 * DEFAULT_ROW_SAMPLES as u64;
+            let key_on = DspEvent { cycle: sample * 32, sample, kind: DspEventKind::KeyOn { voice: 0 } };
+            recorder.process(sample, &[key_on], &dsp_state(&[voice_state(true, srcn, 0x1000, 0x7FF)]));
+        }
+        let bytes = recorder.finish(256 * DEFAULT_ROW_SAMPLES as u64, "");
+
+        assert_eq!(read_u16(&bytes, 72), MAX_INSTRUMENTS as u16);
+        // notes of SRCNs without an instrument are dropped, and the last note is keyed off
+        let notes = cells(&bytes).into_iter().filter(|&(note, _)| note != 0).collect::<Vec<_>>();
+        assert_eq!(notes.len(), MAX_INSTRUMENTS + 1);
+        assert!(notes[..128].iter().zip(1..).all(|(&(note, instrument), expected)| note != KEY_OFF && instrument == expected));
+        assert_eq!(notes[128], (KEY_OFF, 0));
+
+        // instrument headers follow patterns to the end of file
+        let mut pos = PATTERN_ADDR;
+        for _ in 0..read_u16(&bytes, 70) {
+            pos += 9 + read_u16(&bytes, pos + 7) as usize;
+        }
+        for _ in 0..MAX_INSTRUMENTS {
+            assert_eq!(&bytes[pos..pos + 4], &INSTRUMENT_HEADER_SIZE.to_le_bytes());
+            let sample_len = u32::from_le_bytes(bytes[pos + 263..pos + 267].try_into().unwrap()) as usize;
+            pos += INSTRUMENT_HEADER_SIZE as usize + SAMPLE_HEADER_SIZE as usize + sample_len;
+        }
+        assert_eq!(pos, bytes.len());
+    }
+}